@@ -57,6 +57,9 @@ namespace VfioService
                         case GaCmdOut.MessageOneofCase.SetMousePosition:
                             Cursor.Position = new System.Drawing.Point(outCmd.SetMousePosition.X, outCmd.SetMousePosition.Y);
                             break;
+                        case GaCmdOut.MessageOneofCase.Shutdown:
+                            System.Diagnostics.Process.Start("shutdown.exe", "/s /t 0");
+                            break;
                     }
                 }
             }).Start();
@@ -33,23 +33,24 @@ namespace ClientpipeProtocol {
             "DkNsaXBib2FyZFR5cGVzEjEKBXR5cGVzGAEgAygOMiIuY2xpZW50cGlwZV9w",
             "cm90b2NvbC5DbGlwYm9hcmRUeXBlIjwKDlJlZ2lzdGVySG90S2V5EgoKAmlk",
             "GAEgASgNEhEKCW1vZGlmaWVycxgCIAEoDRILCgNrZXkYAyABKA0iHQoFUG9p",
-            "bnQSCQoBeBgBIAEoBRIJCgF5GAIgASgFItQCCghHYUNtZE91dBImCgRwaW5n",
+            "bnQSCQoBeBgBIAEoBRIJCgF5GAIgASgFIoADCghHYUNtZE91dBImCgRwaW5n",
             "GAEgASgLMhYuZ29vZ2xlLnByb3RvYnVmLkVtcHR5SAASOgoJY2xpcGJvYXJk",
             "GAIgASgLMiUuY2xpZW50cGlwZV9wcm90b2NvbC5DbGlwYm9hcmRNZXNzYWdl",
             "SAASMwoRcmVsZWFzZV9tb2RpZmllcnMYECABKAsyFi5nb29nbGUucHJvdG9i",
             "dWYuRW1wdHlIABIpCgdzdXNwZW5kGBEgASgLMhYuZ29vZ2xlLnByb3RvYnVm",
             "LkVtcHR5SAASPwoQcmVnaXN0ZXJfaG90X2tleRgSIAEoCzIjLmNsaWVudHBp",
             "cGVfcHJvdG9jb2wuUmVnaXN0ZXJIb3RLZXlIABI4ChJzZXRfbW91c2VfcG9z",
-            "aXRpb24YEyABKAsyGi5jbGllbnRwaXBlX3Byb3RvY29sLlBvaW50SABCCQoH",
-            "bWVzc2FnZSK9AgoHR2FDbWRJbhImCgRwb25nGAEgASgLMhYuZ29vZ2xlLnBy",
-            "b3RvYnVmLkVtcHR5SAASLQoLcmVwb3J0X2Jvb3QYAiABKAsyFi5nb29nbGUu",
-            "cHJvdG9idWYuRW1wdHlIABI6CgljbGlwYm9hcmQYAyABKAsyJS5jbGllbnRw",
-            "aXBlX3Byb3RvY29sLkNsaXBib2FyZE1lc3NhZ2VIABIsCgpzdXNwZW5kaW5n",
-            "GBAgASgLMhYuZ29vZ2xlLnByb3RvYnVmLkVtcHR5SAASEQoHaG90X2tleRgR",
-            "IAEoDUgAEiAKFmhvdF9rZXlfYmluZGluZ19mYWlsZWQYEiABKAlIABIxCgtt",
-            "b3VzZV9lZGdlZBgTIAEoCzIaLmNsaWVudHBpcGVfcHJvdG9jb2wuUG9pbnRI",
-            "AEIJCgdtZXNzYWdlKi4KDUNsaXBib2FyZFR5cGUSCAoETm9uZRAAEggKBFRl",
-            "eHQQARIJCgVJbWFnZRACYgZwcm90bzM="));
+            "aXRpb24YEyABKAsyGi5jbGllbnRwaXBlX3Byb3RvY29sLlBvaW50SAASKgoI",
+            "c2h1dGRvd24YFCABKAsyFi5nb29nbGUucHJvdG9idWYuRW1wdHlIAEIJCgdt",
+            "ZXNzYWdlIr0CCgdHYUNtZEluEiYKBHBvbmcYASABKAsyFi5nb29nbGUucHJv",
+            "dG9idWYuRW1wdHlIABItCgtyZXBvcnRfYm9vdBgCIAEoCzIWLmdvb2dsZS5w",
+            "cm90b2J1Zi5FbXB0eUgAEjoKCWNsaXBib2FyZBgDIAEoCzIlLmNsaWVudHBp",
+            "cGVfcHJvdG9jb2wuQ2xpcGJvYXJkTWVzc2FnZUgAEiwKCnN1c3BlbmRpbmcY",
+            "ECABKAsyFi5nb29nbGUucHJvdG9idWYuRW1wdHlIABIRCgdob3Rfa2V5GBEg",
+            "ASgNSAASIAoWaG90X2tleV9iaW5kaW5nX2ZhaWxlZBgSIAEoCUgAEjEKC21v",
+            "dXNlX2VkZ2VkGBMgASgLMhouY2xpZW50cGlwZV9wcm90b2NvbC5Qb2ludEgA",
+            "QgkKB21lc3NhZ2UqLgoNQ2xpcGJvYXJkVHlwZRIICgROb25lEAASCAoEVGV4",
+            "dBABEgkKBUltYWdlEAJiBnByb3RvMw=="));
       descriptor = pbr::FileDescriptor.FromGeneratedCode(descriptorData,
           new pbr::FileDescriptor[] { global::Google.Protobuf.WellKnownTypes.EmptyReflection.Descriptor, },
           new pbr::GeneratedClrTypeInfo(new[] {typeof(global::ClientpipeProtocol.ClipboardType), }, new pbr::GeneratedClrTypeInfo[] {
@@ -57,7 +58,7 @@ namespace ClientpipeProtocol {
             new pbr::GeneratedClrTypeInfo(typeof(global::ClientpipeProtocol.ClipboardTypes), global::ClientpipeProtocol.ClipboardTypes.Parser, new[]{ "Types_" }, null, null, null),
             new pbr::GeneratedClrTypeInfo(typeof(global::ClientpipeProtocol.RegisterHotKey), global::ClientpipeProtocol.RegisterHotKey.Parser, new[]{ "Id", "Modifiers", "Key" }, null, null, null),
             new pbr::GeneratedClrTypeInfo(typeof(global::ClientpipeProtocol.Point), global::ClientpipeProtocol.Point.Parser, new[]{ "X", "Y" }, null, null, null),
-            new pbr::GeneratedClrTypeInfo(typeof(global::ClientpipeProtocol.GaCmdOut), global::ClientpipeProtocol.GaCmdOut.Parser, new[]{ "Ping", "Clipboard", "ReleaseModifiers", "Suspend", "RegisterHotKey", "SetMousePosition" }, new[]{ "Message" }, null, null),
+            new pbr::GeneratedClrTypeInfo(typeof(global::ClientpipeProtocol.GaCmdOut), global::ClientpipeProtocol.GaCmdOut.Parser, new[]{ "Ping", "Clipboard", "ReleaseModifiers", "Suspend", "RegisterHotKey", "SetMousePosition", "Shutdown" }, new[]{ "Message" }, null, null),
             new pbr::GeneratedClrTypeInfo(typeof(global::ClientpipeProtocol.GaCmdIn), global::ClientpipeProtocol.GaCmdIn.Parser, new[]{ "Pong", "ReportBoot", "Clipboard", "Suspending", "HotKey", "HotKeyBindingFailed", "MouseEdged" }, new[]{ "Message" }, null, null)
           }));
     }
@@ -794,6 +795,9 @@ namespace ClientpipeProtocol {
         case MessageOneofCase.SetMousePosition:
           SetMousePosition = other.SetMousePosition.Clone();
           break;
+        case MessageOneofCase.Shutdown:
+          Shutdown = other.Shutdown.Clone();
+          break;
       }
 
     }
@@ -869,6 +873,17 @@ namespace ClientpipeProtocol {
       }
     }
 
+    /// <summary>Field number for the "shutdown" field.</summary>
+    public const int ShutdownFieldNumber = 20;
+    [global::System.Diagnostics.DebuggerNonUserCodeAttribute]
+    public global::Google.Protobuf.WellKnownTypes.Empty Shutdown {
+      get { return messageCase_ == MessageOneofCase.Shutdown ? (global::Google.Protobuf.WellKnownTypes.Empty) message_ : null; }
+      set {
+        message_ = value;
+        messageCase_ = value == null ? MessageOneofCase.None : MessageOneofCase.Shutdown;
+      }
+    }
+
     private object message_;
     /// <summary>Enum of possible cases for the "message" oneof.</summary>
     public enum MessageOneofCase {
@@ -879,6 +894,7 @@ namespace ClientpipeProtocol {
       Suspend = 17,
       RegisterHotKey = 18,
       SetMousePosition = 19,
+      Shutdown = 20,
     }
     private MessageOneofCase messageCase_ = MessageOneofCase.None;
     [global::System.Diagnostics.DebuggerNonUserCodeAttribute]
@@ -911,6 +927,7 @@ namespace ClientpipeProtocol {
       if (!object.Equals(Suspend, other.Suspend)) return false;
       if (!object.Equals(RegisterHotKey, other.RegisterHotKey)) return false;
       if (!object.Equals(SetMousePosition, other.SetMousePosition)) return false;
+      if (!object.Equals(Shutdown, other.Shutdown)) return false;
       if (MessageCase != other.MessageCase) return false;
       return true;
     }
@@ -924,6 +941,7 @@ namespace ClientpipeProtocol {
       if (messageCase_ == MessageOneofCase.Suspend) hash ^= Suspend.GetHashCode();
       if (messageCase_ == MessageOneofCase.RegisterHotKey) hash ^= RegisterHotKey.GetHashCode();
       if (messageCase_ == MessageOneofCase.SetMousePosition) hash ^= SetMousePosition.GetHashCode();
+      if (messageCase_ == MessageOneofCase.Shutdown) hash ^= Shutdown.GetHashCode();
       hash ^= (int) messageCase_;
       return hash;
     }
@@ -959,6 +977,10 @@ namespace ClientpipeProtocol {
         output.WriteRawTag(154, 1);
         output.WriteMessage(SetMousePosition);
       }
+      if (messageCase_ == MessageOneofCase.Shutdown) {
+        output.WriteRawTag(162, 1);
+        output.WriteMessage(Shutdown);
+      }
     }
 
     [global::System.Diagnostics.DebuggerNonUserCodeAttribute]
@@ -982,6 +1004,9 @@ namespace ClientpipeProtocol {
       if (messageCase_ == MessageOneofCase.SetMousePosition) {
         size += 2 + pb::CodedOutputStream.ComputeMessageSize(SetMousePosition);
       }
+      if (messageCase_ == MessageOneofCase.Shutdown) {
+        size += 2 + pb::CodedOutputStream.ComputeMessageSize(Shutdown);
+      }
       return size;
     }
 
@@ -1009,6 +1034,9 @@ namespace ClientpipeProtocol {
         case MessageOneofCase.SetMousePosition:
           SetMousePosition = other.SetMousePosition;
           break;
+        case MessageOneofCase.Shutdown:
+          Shutdown = other.Shutdown;
+          break;
       }
 
     }
@@ -1075,6 +1103,15 @@ namespace ClientpipeProtocol {
             SetMousePosition = subBuilder;
             break;
           }
+          case 162: {
+            global::Google.Protobuf.WellKnownTypes.Empty subBuilder = new global::Google.Protobuf.WellKnownTypes.Empty();
+            if (messageCase_ == MessageOneofCase.Shutdown) {
+              subBuilder.MergeFrom(Shutdown);
+            }
+            input.ReadMessage(subBuilder);
+            Shutdown = subBuilder;
+            break;
+          }
         }
       }
     }
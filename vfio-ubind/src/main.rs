@@ -1,23 +1,423 @@
 #[macro_use] extern crate log;
+#[macro_use] extern crate serde_derive;
 extern crate argparse;
+extern crate dbus;
 extern crate env_logger;
 extern crate users;
+extern crate serde;
+extern crate serde_json;
 
-use std::path::Path;
+mod policy;
+mod service;
+
+use std::path::{Path, PathBuf};
 use std::fs::{OpenOptions, self};
 use std::io::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::os::unix::net::UnixDatagram;
+use std::process;
 
-use argparse::{ArgumentParser, StoreTrue, Store};
+use argparse::{ArgumentParser, StoreTrue, Store, List};
 use users::os::unix::GroupExt;
 
-fn pretty_write<P: AsRef<Path>>(path: P, content: &str, dryrun: bool) {
+use policy::Policy;
+
+/// PCI class (base class and subclass) of bridges, those always stay with the host
+const PCI_CLASS_BRIDGE: &str = "0x0604";
+
+/// How long a driver may take to pick up a device
+const BIND_TIMEOUT: u64 = 5;
+
+/// Everything we touch on the host, below `--root` so we can be pointed at a fake tree
+struct Paths {
+    pci: PathBuf,
+    /// Where we remember which driver a device had before we took it, one file per device
+    state: PathBuf,
+    policy: PathBuf,
+}
+
+impl Paths {
+    fn new(root: &Path) -> Paths {
+        Paths {
+            pci: root.join("sys/bus/pci"),
+            state: root.join("run/vfio-ubind"),
+            policy: root.join("etc/windows-gaming/vfio-ubind.conf"),
+        }
+    }
+}
+
+/// What happened to one device of a group
+#[derive(Serialize, Debug, PartialEq)]
+struct MemberReport {
+    slot: String,
+    driver_before: Option<String>,
+    driver_after: Option<String>,
+}
+
+/// What happened to one of the devices we were asked about and the rest of its group
+#[derive(Serialize, Debug, PartialEq)]
+struct DeviceReport {
+    device: String,
+    members: Vec<MemberReport>,
+    /// Why not everything went as planned
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    remove: bool,
+    dry_run: bool,
+    devices: Vec<DeviceReport>,
+}
+
+/// Sends a message to syslog (journald listens there too), best effort
+fn syslog(msg: &str) {
+    // facility auth, severity notice
+    let line = format!("<37>vfio-ubind[{}]: {}", process::id(), msg);
+    if let Ok(socket) = UnixDatagram::unbound() {
+        let _ = socket.send_to(line.as_bytes(), "/dev/log");
+    }
+}
+
+fn pretty_write<P: AsRef<Path>>(path: P, content: &str, dryrun: bool) -> Result<(), String> {
     let path = path.as_ref();
     info!("writing {} into {}", content, path.display());
 
     if !dryrun {
-        let mut file = OpenOptions::new().write(true).open(&path).expect(&format!("Failed to open {}", path.display()));
-        if let Err(e) = write!(&mut file, "{}", content) {
-            panic!("Failed to write {} into {}! Got: {}", content, path.display(), e)
+        let mut file = OpenOptions::new().write(true).truncate(true).open(&path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        write!(&mut file, "{}", content)
+            .map_err(|e| format!("Failed to write {} into {}! Got: {}", content.trim(), path.display(), e))?;
+    }
+    Ok(())
+}
+
+fn read_trimmed(path: &Path) -> Result<String, String> {
+    let mut content = String::new();
+    fs::File::open(path).and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(content.trim().to_owned())
+}
+
+/// Whether this looks like `0000:01:00.0`, anything else could point us out of sysfs
+fn valid_slot(slot: &str) -> bool {
+    let parts: Vec<_> = slot.split(|c| c == ':' || c == '.').collect();
+    parts.len() == 4 && parts.iter().zip(&[4, 2, 2, 1]).all(|(part, &len)| {
+        part.len() == len && part.chars().all(|c| c.is_digit(16))
+    })
+}
+
+/// A device in the same IOMMU group as the one we were asked about (including that one)
+struct Member {
+    slot: String,
+    sysfs: PathBuf,
+}
+
+impl Member {
+    fn driver(&self) -> Option<String> {
+        fs::read_link(self.sysfs.join("driver")).ok()
+            .and_then(|x| x.file_name().map(|x| x.to_string_lossy().into_owned()))
+    }
+
+    fn read_id(&self, name: &str) -> Result<u16, String> {
+        let id = read_trimmed(&self.sysfs.join(name))?;
+        u16::from_str_radix(id.trim_left_matches("0x"), 16)
+            .map_err(|_| format!("Invalid {} id of {}: {}", name, self.slot, id))
+    }
+
+    fn is_bridge(&self) -> Result<bool, String> {
+        Ok(read_trimmed(&self.sysfs.join("class"))?.starts_with(PCI_CLASS_BRIDGE))
+    }
+}
+
+/// All endpoints of the device's IOMMU group, as they have to go to the VM together
+fn group_endpoints(dev_sysfs: &Path) -> Result<Vec<Member>, String> {
+    let read_err = |e| format!("Failed to read the device's IOMMU group: {}", e);
+    let mut members = Vec::new();
+    for entry in dev_sysfs.join("iommu_group").join("devices").read_dir().map_err(read_err)? {
+        let entry = entry.map_err(read_err)?;
+        let member = Member { slot: entry.file_name().to_string_lossy().into_owned(), sysfs: entry.path() };
+        if member.is_bridge()? {
+            info!("{} is a bridge, leaving it alone", member.slot);
+        } else {
+            members.push(member);
+        }
+    }
+    members.sort_by(|a, b| a.slot.cmp(&b.slot));
+    Ok(members)
+}
+
+struct Ubind<'a> {
+    paths: &'a Paths,
+    policy: &'a Policy,
+    dryrun: bool,
+    remove: bool,
+    /// Don't tell the user as we go, they get the report at the end
+    quiet: bool,
+}
+
+impl<'a> Ubind<'a> {
+    /// Tells the user what we did, and syslog too as this runs with root privileges
+    fn report(&self, msg: &str) {
+        if !self.quiet {
+            println!("{}", msg);
+        }
+        syslog(msg);
+    }
+
+    fn state_file(&self, member: &Member) -> PathBuf {
+        self.paths.state.join(&member.slot)
+    }
+
+    fn save_driver(&self, member: &Member, driver: &str) -> Result<(), String> {
+        info!("remembering {} as the driver of {}", driver, member.slot);
+        if !self.dryrun {
+            fs::create_dir_all(&self.paths.state)
+                .map_err(|e| format!("Failed to create {}: {}", self.paths.state.display(), e))?;
+            fs::File::create(self.state_file(member)).and_then(|mut f| f.write_all(driver.as_bytes()))
+                .map_err(|e| format!("Failed to write {}: {}", self.state_file(member).display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// The driver the device had before we bound it to vfio-pci, if we know it
+    fn saved_driver(&self, member: &Member) -> Option<String> {
+        read_trimmed(&self.state_file(member)).ok().filter(|x| !x.is_empty())
+    }
+
+    fn forget_driver(&self, member: &Member) {
+        if !self.dryrun {
+            let _ = fs::remove_file(self.state_file(member));
+        }
+    }
+
+    /// Waits for `expected` (or any driver) to take the device, returning whatever it ends up with
+    fn wait_for_driver(&self, member: &Member, expected: Option<&str>) -> Option<String> {
+        let start = Instant::now();
+        loop {
+            let driver = member.driver();
+            let done = match (expected, &driver) {
+                (Some(expected), &Some(ref driver)) => expected == driver,
+                (None, &Some(_)) => true,
+                (_, &None) => false,
+            };
+            if done || self.dryrun || start.elapsed() >= Duration::from_secs(BIND_TIMEOUT) {
+                return driver;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn batch(&self, devices: &[String]) -> Report {
+        Report {
+            remove: self.remove,
+            dry_run: self.dryrun,
+            devices: devices.iter().map(|x| self.run(x)).collect(),
+        }
+    }
+
+    /// Binds (or releases) a device along with the rest of its IOMMU group.
+    ///
+    /// Failures end up in the report, so one bad device doesn't stop the others in the batch.
+    fn run(&self, device: &str) -> DeviceReport {
+        let mut report = DeviceReport { device: device.to_owned(), members: Vec::new(), error: None };
+        if let Err(e) = self.rebind(device, &mut report.members) {
+            syslog(&format!("{}: {}", device, e));
+            if !self.quiet {
+                eprintln!("{}: {}", device, e);
+            }
+            report.error = Some(e);
+        }
+        report
+    }
+
+    /// Frees a member for the new driver, returning whether it has to be probed afterwards
+    fn unbind(&self, member: &Member, driver: Option<String>) -> Result<bool, String> {
+        match driver {
+            Some(ref driver) if driver == "vfio-pci" && !self.remove => {
+                self.report(&format!("{}: already bound to vfio-pci", member.slot));
+                Ok(false)
+            }
+            Some(driver) => {
+                if self.remove && driver != "vfio-pci" {
+                    self.report(&format!("{}: not bound to vfio-pci but {}, leaving it alone", member.slot,
+                                         driver));
+                    return Ok(false);
+                }
+                if !self.remove {
+                    self.save_driver(member, &driver)?;
+                }
+                let driver_override = if self.remove { "\n" } else { "vfio-pci" };
+                pretty_write(member.sysfs.join("driver_override"), driver_override, self.dryrun)?;
+                pretty_write(member.sysfs.join("driver/unbind"), &member.slot, self.dryrun)?;
+                self.report(&format!("{}: unbound from {}", member.slot, driver));
+                Ok(true)
+            }
+            None if self.remove => match self.saved_driver(member) {
+                // an earlier attempt to give it back got as far as taking it from vfio-pci
+                Some(driver) => {
+                    pretty_write(member.sysfs.join("driver_override"), "\n", self.dryrun)?;
+                    self.report(&format!("{}: has no driver, giving it back to {}", member.slot, driver));
+                    Ok(true)
+                }
+                None => {
+                    self.report(&format!("{}: has no driver, leaving it alone", member.slot));
+                    Ok(false)
+                }
+            },
+            None => {
+                pretty_write(member.sysfs.join("driver_override"), "vfio-pci", self.dryrun)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Hands members back to the drivers they had before, after we failed with part of a group.
+    ///
+    /// Best effort, all we can do about failures is to tell.
+    fn roll_back(&self, members: &[(usize, Member)], report: &mut [MemberReport]) {
+        for &(i, ref member) in members.iter().rev() {
+            let driver_override = if self.remove { "vfio-pci" } else { "\n" };
+            if let Err(e) = pretty_write(member.sysfs.join("driver_override"), driver_override, self.dryrun) {
+                self.report(&format!("{}: failed to roll back: {}", member.slot, e));
+            }
+            // those that didn't get away from their driver can stay where they are
+            let before = report[i].driver_before.clone();
+            if report[i].driver_after != before {
+                if report[i].driver_after.is_some() {
+                    match pretty_write(member.sysfs.join("driver/unbind"), &member.slot, self.dryrun) {
+                        Ok(()) => report[i].driver_after = None,
+                        Err(e) => self.report(&format!("{}: failed to roll back: {}", member.slot, e)),
+                    }
+                }
+                match before {
+                    Some(ref driver) if report[i].driver_after.is_none() => {
+                        let bind = self.paths.pci.join("drivers").join(driver).join("bind");
+                        match pretty_write(bind, &member.slot, self.dryrun) {
+                            Ok(()) => report[i].driver_after = self.wait_for_driver(member, Some(driver)),
+                            Err(e) => self.report(&format!("{}: failed to roll back: {}", member.slot, e)),
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            if !self.remove {
+                self.forget_driver(member);
+            }
+            match report[i].driver_after {
+                Some(ref driver) => self.report(&format!("{}: rolled back, bound to {}", member.slot, driver)),
+                None => self.report(&format!("{}: rolled back, without driver", member.slot)),
+            }
+        }
+    }
+
+    fn rebind(&self, device: &str, report: &mut Vec<MemberReport>) -> Result<(), String> {
+        if !valid_slot(device) {
+            return Err("Not a PCI slot, expected something like 0000:01:00.0".to_owned());
+        }
+        let dev_sysfs = self.paths.pci.join("devices").join(device);
+        if !dev_sysfs.exists() {
+            return Err("Failed to look up the given device (does it exist?)!".to_owned());
+        }
+
+        let dev_iommu = dev_sysfs.join("iommu");
+        if !dev_iommu.exists() {
+            info!("File {} didn't exist", dev_iommu.display());
+            return Err("No signs of an IOMMU. \
+                        Check your hardware and/or linux cmdline parameters. \
+                        Use `intel_iommu=on` or `iommu=pt iommu=1`".to_owned());
+        }
+
+        let dev_reset = dev_sysfs.join("reset");
+        if !dev_reset.exists() {
+            info!("File {} didn't exist", dev_reset.display());
+            return Err("The device does not support resetting!".to_owned());
+        }
+
+        // checked for the whole group, as all of it changes hands
+        let members = group_endpoints(&dev_sysfs)?;
+        let mut forbidden = Vec::new();
+        for member in &members {
+            if !self.policy.allows(&member.slot, member.read_id("vendor")?, member.read_id("device")?) {
+                forbidden.push(member.slot.as_str());
+            }
+        }
+        if !forbidden.is_empty() {
+            return Err(format!("refused, {} not allowed by {}", forbidden.join(", "), self.paths.policy.display()));
+        }
+
+        // unbind everything first, a group is only usable once all of it is free
+        let mut probe = Vec::new();
+        for member in members {
+            let driver = member.driver();
+            report.push(MemberReport {
+                slot: member.slot.clone(),
+                driver_before: driver.clone(),
+                driver_after: driver.clone(),
+            });
+            match self.unbind(&member, driver) {
+                Ok(true) => {
+                    report.last_mut().unwrap().driver_after = None;
+                    probe.push((report.len() - 1, member));
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    // this one might be halfway through as well
+                    probe.push((report.len() - 1, member));
+                    self.roll_back(&probe, report);
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut failed = Vec::new();
+        for &(i, ref member) in &probe {
+            let expected = if self.remove { self.saved_driver(member) } else { Some("vfio-pci".to_owned()) };
+            let written = match (self.remove, &expected) {
+                // binding directly is the only way to get drivers that don't claim the device by id
+                (true, &Some(ref driver)) => {
+                    let bind = self.paths.pci.join("drivers").join(driver).join("bind");
+                    pretty_write(bind, &member.slot, self.dryrun)
+                }
+                _ => pretty_write(self.paths.pci.join("drivers_probe"), &member.slot, self.dryrun),
+            };
+            // the kernel fails the write if the driver doesn't want it, the others still get their chance
+            if let Err(e) = written {
+                self.report(&format!("{}: {}", member.slot, e));
+                failed.push(member.slot.clone());
+                continue;
+            }
+
+            let driver = self.wait_for_driver(member, expected.as_ref().map(|x| x.as_str()));
+            match (&driver, &expected) {
+                (&Some(ref driver), &Some(ref expected)) if driver != expected && !self.dryrun => {
+                    self.report(&format!("{}: expected {} to take it, but it went to {}", member.slot, expected,
+                                         driver));
+                    failed.push(member.slot.clone());
+                }
+                (&None, &Some(ref expected)) if !self.dryrun => {
+                    self.report(&format!("{}: {} didn't take it, it has no driver now", member.slot, expected));
+                    failed.push(member.slot.clone());
+                }
+                (&Some(ref driver), _) => self.report(&format!("{}: now bound to {}", member.slot, driver)),
+                (&None, _) => self.report(&format!("{}: now without driver", member.slot)),
+            }
+            report[i].driver_after = driver;
+            // without it, there would be no way to retry giving back the device
+            if self.remove && !failed.contains(&member.slot) {
+                self.forget_driver(member);
+            }
+        }
+
+        // the group is only of use as a whole, so the host gets all of it back
+        if !failed.is_empty() && !self.remove {
+            self.roll_back(&probe, report);
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Failed to rebind {}!", failed.join(", ")))
         }
     }
 }
@@ -25,14 +425,24 @@ fn pretty_write<P: AsRef<Path>>(path: P, content: &str, dryrun: bool) {
 fn main() {
     let mut dryrun = false;
     let mut remove = false;
-    let mut device = "".to_string();
+    let mut json = false;
+    let mut dbus = false;
+    let mut root = "/".to_string();
+    let mut devices: Vec<String> = Vec::new();
     
     {
         let mut ap = ArgumentParser::new();
-        ap.set_description("This tool allows you to bind the vfio driver to a specified resettable pci-device");
-        ap.refer(&mut device).add_argument("PCI-Device", Store, "The PCI-Device to bind to").required();
+        ap.set_description("This tool allows you to bind the vfio driver to the specified resettable pci-devices \
+                            and everything else in their IOMMU groups");
+        ap.refer(&mut devices).add_argument("PCI-Device", List, "The PCI-Devices to bind to");
         ap.refer(&mut dryrun).add_option(&["-d", "--dry-run"], StoreTrue, "Don't change anything");
-        ap.refer(&mut remove).add_option(&["-r", "--remove"], StoreTrue, "Reattach the default Driver to the device");
+        ap.refer(&mut remove).add_option(&["-r", "--remove"], StoreTrue,
+                                         "Reattach the previous Driver to the devices");
+        ap.refer(&mut json).add_option(&["-j", "--json"], StoreTrue, "Print a JSON report instead of prose");
+        ap.refer(&mut dbus).add_option(&["--dbus"], StoreTrue,
+                                       "Serve the D-Bus interface on the system bus (for D-Bus activation)");
+        ap.refer(&mut root).add_option(&["--root"], Store,
+                                       "Look for sys/, run/ and etc/ in this directory instead (for testing)");
         ap.parse_args_or_exit();
     }
     
@@ -40,58 +450,333 @@ fn main() {
     
     debug!("effective uid: {} current uid: {}", users::get_effective_uid(), users::get_current_uid());
     
-    if users::get_effective_uid() != 0 {
-        panic!("This tool requires root permissions. If the setuid bit is not set, you need to execute this as root!");
-    }
-
-    if users::get_current_uid() != 0 {
-        let vfio_group = users::get_group_by_name("vfio")
-            .expect("Your system has no vfio group. You need to be part of it to run this tool!");
-        let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
-        let user_name = user.name();
-        
-        if !vfio_group.members().contains(&user_name.to_owned()) {
-            panic!("You're not part of the vfio group, so you're not allowed to use this tool!");
-        } else {
-            debug!("User is part of the vfio group...continuing");
+    // a fake tree is only for testing, with our privileges it would let anyone write anywhere
+    let fake_root = Path::new(&root) != Path::new("/");
+    if fake_root && users::get_effective_uid() != users::get_current_uid() {
+        panic!("--root can't be used with elevated privileges!");
+    }
+
+    if dbus {
+        // polkit decides who may do what, we only need to be root ourselves
+        if !fake_root && users::get_effective_uid() != 0 {
+            panic!("The D-Bus service has to run as root!");
+        }
+        if let Err(e) = service::serve(Paths::new(Path::new(&root)), fake_root) {
+            syslog(&e);
+            panic!("{}", e);
         }
+        return;
+    }
+    if devices.is_empty() {
+        eprintln!("Usage: vfio-ubind [OPTIONS] PCI-Device [...]");
+        process::exit(2);
     }
 
-    let dev_sysfs = Path::new("/sys/bus/pci/devices/").read_dir().unwrap()
-        .map(Result::unwrap).find(|x| x.file_name() == *device).map(|x| x.path())
-        .expect("Failed to look up the given device (does it exist?)!");
-    let dev_iommu = dev_sysfs.join("iommu");
-    
-    if !dev_iommu.exists() {
-        info!("File {} didn't exist", dev_iommu.display());
-        panic!("No signs of an IOMMU. \
-                Check your hardware and/or linux cmdline parameters. \
-                Use `intel_iommu=on` or `iommu=pt iommu=1`");
+    let user = users::get_user_by_uid(users::get_current_uid()).map(|x| x.name().to_owned())
+        .unwrap_or_else(|| users::get_current_uid().to_string());
+    syslog(&format!("{} requested to {} {}{}", user, if remove { "release" } else { "bind" }, devices.join(", "),
+                    if dryrun { " (dry run)" } else { "" }));
+
+    if !fake_root {
+        if users::get_effective_uid() != 0 {
+            panic!("This tool requires root permissions. If the setuid bit is not set, you need to execute this as root!");
+        }
+
+        if users::get_current_uid() != 0 {
+            let vfio_group = users::get_group_by_name("vfio")
+                .expect("Your system has no vfio group. You need to be part of it to run this tool!");
+            
+            if !vfio_group.members().contains(&user) {
+                syslog(&format!("refused {}: not in the vfio group", user));
+                panic!("You're not part of the vfio group, so you're not allowed to use this tool!");
+            } else {
+                debug!("User is part of the vfio group...continuing");
+            }
+        }
     }
-    
-    let dev_reset = dev_sysfs.join("reset");
-    
-    if !dev_reset.exists() {
-        info!("File {} didn't exist", dev_reset.display());
-        panic!("The device does not support resetting!");
+
+    let paths = Paths::new(Path::new(&root));
+    let policy = Policy::load(&paths.policy, !fake_root).unwrap_or_else(|e| {
+        syslog(&format!("refused {}: {}", devices.join(", "), e));
+        panic!("{}\nThe administrator has to list the devices vfio-ubind may rebind there.", e)
+    });
+
+    let ubind = Ubind { paths: &paths, policy: &policy, dryrun, remove, quiet: json };
+    let report = ubind.batch(&devices);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
     }
-    
-    let dev_driver_link = dev_sysfs.join("driver");
-    let dev_driver = fs::read_link(dev_driver_link);
-    
-    if let Ok(driver) = dev_driver {
-        if driver.file_name().unwrap() == "vfio-pci" && !remove {
-            info!("Device already bound to vfio-pci driver, nothing to do here");
-            return;
-        }
-        info!("Device already has a driver, unbinding");
-        
-        let driver = if remove { "\n" } else { "vfio-pci" };
-        pretty_write(dev_sysfs.join("driver_override"), driver, dryrun);
-        pretty_write(dev_sysfs.join("driver/unbind"), &device, dryrun);		
-    } else if !remove {
-        pretty_write(dev_sysfs.join("driver_override"), "vfio-pci", dryrun);
+    if report.devices.iter().any(|x| x.error.is_some()) {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A sysfs with a GPU, its audio function and the root port above them, all in one IOMMU group
+    pub fn fake_tree(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("vfio-ubind-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let pci = root.join("sys/bus/pci");
+        let group = root.join("sys/kernel/iommu_groups/1");
+        fs::create_dir_all(group.join("devices")).unwrap();
+        fs::create_dir_all(pci.join("drivers/vfio-pci")).unwrap();
+        fs::File::create(pci.join("drivers_probe")).unwrap();
+        for &(slot, class, id, driver) in &[("0000:00:01.0", "0x060400", "0x1901", "pcieport"),
+                                            ("0000:01:00.0", "0x030000", "0x1b80", "nouveau"),
+                                            ("0000:01:00.1", "0x040300", "0x10f0", "snd_hda_intel")] {
+            let dev = pci.join("devices").join(slot);
+            fs::create_dir_all(&dev).unwrap();
+            fs::create_dir_all(pci.join("drivers").join(driver)).unwrap();
+            for &(file, content) in &[("class", class), ("vendor", "0x10de"), ("device", id), ("iommu", ""),
+                                      ("reset", ""), ("driver_override", "(null)")] {
+                fs::File::create(dev.join(file)).unwrap().write_all(content.as_bytes()).unwrap();
+            }
+            symlink(pci.join("drivers").join(driver), dev.join("driver")).unwrap();
+            symlink(&group, dev.join("iommu_group")).unwrap();
+            symlink(&dev, group.join("devices").join(slot)).unwrap();
+        }
+        for driver in fs::read_dir(pci.join("drivers")).unwrap() {
+            let driver = driver.unwrap().path();
+            fs::File::create(driver.join("bind")).unwrap();
+            fs::File::create(driver.join("unbind")).unwrap();
+        }
+        root
+    }
+
+    /// Plays the kernel's part for a fake tree, acting on whatever is written to bind, unbind and drivers_probe
+    struct FakeKernel {
+        stop: Arc<AtomicBool>,
+        thread: thread::JoinHandle<Vec<String>>,
+    }
+
+    /// Empties a file the kernel would act on, returning what was written there
+    fn take(path: &Path) -> Option<String> {
+        // there's no bind file for a driver that isn't loaded
+        let content = read_trimmed(path).ok()?;
+        if content.is_empty() {
+            return None;
+        }
+        fs::File::create(path).unwrap();
+        Some(content)
+    }
+
+    fn link(pci: &Path, slot: &str, driver: &Path) {
+        let link = pci.join("devices").join(slot).join("driver");
+        let _ = fs::remove_file(&link);
+        symlink(driver, link).unwrap();
+    }
+
+    impl FakeKernel {
+        fn start(root: &Path) -> FakeKernel {
+            let pci = root.join("sys/bus/pci");
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopped = stop.clone();
+            let thread = thread::spawn(move || {
+                let mut log = Vec::new();
+                loop {
+                    // whatever was written before we were stopped still gets done
+                    let stopping = stopped.load(Ordering::SeqCst);
+                    for driver in fs::read_dir(pci.join("drivers")).unwrap() {
+                        let driver = driver.unwrap().path();
+                        let name = driver.file_name().unwrap().to_string_lossy().into_owned();
+                        if let Some(slot) = take(&driver.join("unbind")) {
+                            let link = pci.join("devices").join(&slot).join("driver");
+                            if fs::read_link(&link).ok().as_ref() == Some(&driver) {
+                                fs::remove_file(link).unwrap();
+                            }
+                            log.push(format!("{}/unbind {}", name, slot));
+                        }
+                        if let Some(slot) = take(&driver.join("bind")) {
+                            link(&pci, &slot, &driver);
+                            log.push(format!("{}/bind {}", name, slot));
+                        }
+                    }
+                    if let Some(slot) = take(&pci.join("drivers_probe")) {
+                        let driver_override = read_trimmed(&pci.join("devices").join(&slot).join("driver_override"));
+                        if driver_override.unwrap() == "vfio-pci" && pci.join("drivers/vfio-pci").exists() {
+                            link(&pci, &slot, &pci.join("drivers/vfio-pci"));
+                        }
+                        log.push(format!("drivers_probe {}", slot));
+                    }
+                    if stopping {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                log
+            });
+            FakeKernel { stop, thread }
+        }
+
+        /// Everything the kernel was asked to do
+        fn stop(self) -> Vec<String> {
+            self.stop.store(true, Ordering::SeqCst);
+            self.thread.join().unwrap()
+        }
+    }
+
+    fn member(slot: &str, before: &str, after: &str) -> MemberReport {
+        MemberReport {
+            slot: slot.to_owned(),
+            driver_before: Some(before.to_owned()),
+            driver_after: Some(after.to_owned()),
+        }
+    }
+
+    #[test]
+    fn group() {
+        let root = fake_tree("group");
+        let paths = Paths::new(&root);
+        let devices = root.join("sys/bus/pci/devices");
+        let policy = Policy::parse("0000:01:00.0\n10de:10f0\n").unwrap();
+        let ubind = Ubind { paths: &paths, policy: &policy, dryrun: false, remove: false, quiet: true };
+        let kernel = FakeKernel::start(&root);
+        assert_eq!(ubind.run("0000:01:00.0"), DeviceReport {
+            device: "0000:01:00.0".to_owned(),
+            members: vec![member("0000:01:00.0", "nouveau", "vfio-pci"),
+                          member("0000:01:00.1", "snd_hda_intel", "vfio-pci")],
+            error: None,
+        });
+        assert!(ubind.run("0000:02:00.0").error.is_some());
+        let log = kernel.stop();
+        assert!(log.contains(&"nouveau/unbind 0000:01:00.0".to_owned()));
+        assert!(log.contains(&"snd_hda_intel/unbind 0000:01:00.1".to_owned()));
+        // the bridge stays with the host
+        assert!(!log.iter().any(|x| x.contains("0000:00:01.0")));
+        assert_eq!(read_trimmed(&devices.join("0000:01:00.0/driver_override")).unwrap(), "vfio-pci");
+        assert_eq!(read_trimmed(&devices.join("0000:01:00.1/driver_override")).unwrap(), "vfio-pci");
+        assert_eq!(read_trimmed(&devices.join("0000:00:01.0/driver_override")).unwrap(), "(null)");
+        assert_eq!(read_trimmed(&paths.state.join("0000:01:00.0")).unwrap(), "nouveau");
+        assert_eq!(read_trimmed(&paths.state.join("0000:01:00.1")).unwrap(), "snd_hda_intel");
+
+        let policy = Policy::parse("0000:01:00.0\n").unwrap();
+        let ubind = Ubind { paths: &paths, policy: &policy, dryrun: false, remove: false, quiet: true };
+        let report = ubind.run("0000:01:00.0");
+        assert!(report.members.is_empty());
+        assert!(report.error.unwrap().contains("0000:01:00.1 not allowed"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dry_run() {
+        let root = fake_tree("dry-run");
+        let paths = Paths::new(&root);
+        let policy = Policy::parse("0000:01:00.0\n10de:10f0\n").unwrap();
+        let ubind = Ubind { paths: &paths, policy: &policy, dryrun: true, remove: false, quiet: true };
+        assert_eq!(ubind.run("0000:01:00.0"), DeviceReport {
+            device: "0000:01:00.0".to_owned(),
+            members: vec![member("0000:01:00.0", "nouveau", "nouveau"),
+                          member("0000:01:00.1", "snd_hda_intel", "snd_hda_intel")],
+            error: None,
+        });
+        assert_eq!(read_trimmed(&root.join("sys/bus/pci/devices/0000:01:00.0/driver_override")).unwrap(),
+                   "(null)");
+        assert!(!paths.state.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn roll_back() {
+        let root = fake_tree("roll-back");
+        let paths = Paths::new(&root);
+        let devices = root.join("sys/bus/pci/devices");
+        // the audio function can't be taken, after the GPU already was
+        fs::remove_file(devices.join("0000:01:00.1/driver_override")).unwrap();
+        let policy = Policy::parse("10de:1b80\n10de:10f0\n").unwrap();
+        let ubind = Ubind { paths: &paths, policy: &policy, dryrun: false, remove: false, quiet: true };
+        let kernel = FakeKernel::start(&root);
+        let report = ubind.run("0000:01:00.0");
+        let log = kernel.stop();
+        assert!(report.error.unwrap().contains("driver_override"));
+        assert_eq!(report.members, vec![member("0000:01:00.0", "nouveau", "nouveau"),
+                                        member("0000:01:00.1", "snd_hda_intel", "snd_hda_intel")]);
+        assert_eq!(log, vec!["nouveau/unbind 0000:01:00.0", "nouveau/bind 0000:01:00.0"]);
+        assert_eq!(read_trimmed(&devices.join("0000:01:00.0/driver_override")).unwrap(), "");
+        assert!(!paths.state.join("0000:01:00.0").exists());
+        assert!(!paths.state.join("0000:01:00.1").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn vfio_not_loaded() {
+        let root = fake_tree("vfio-not-loaded");
+        let paths = Paths::new(&root);
+        let devices = root.join("sys/bus/pci/devices");
+        fs::remove_dir_all(root.join("sys/bus/pci/drivers/vfio-pci")).unwrap();
+        let policy = Policy::parse("10de:1b80\n10de:10f0\n").unwrap();
+        let ubind = Ubind { paths: &paths, policy: &policy, dryrun: false, remove: false, quiet: true };
+        let kernel = FakeKernel::start(&root);
+        let report = ubind.run("0000:01:00.0");
+        let mut log = kernel.stop();
+        assert_eq!(report.error.unwrap(), "Failed to rebind 0000:01:00.0, 0000:01:00.1!");
+        // both went back to where they came from
+        assert_eq!(report.members, vec![member("0000:01:00.0", "nouveau", "nouveau"),
+                                        member("0000:01:00.1", "snd_hda_intel", "snd_hda_intel")]);
+        log.sort();
+        assert_eq!(log, vec!["drivers_probe 0000:01:00.0", "drivers_probe 0000:01:00.1",
+                             "nouveau/bind 0000:01:00.0", "nouveau/unbind 0000:01:00.0",
+                             "snd_hda_intel/bind 0000:01:00.1", "snd_hda_intel/unbind 0000:01:00.1"]);
+        assert_eq!(read_trimmed(&devices.join("0000:01:00.0/driver_override")).unwrap(), "");
+        assert_eq!(read_trimmed(&devices.join("0000:01:00.1/driver_override")).unwrap(), "");
+        assert!(!paths.state.join("0000:01:00.0").exists());
+        assert!(!paths.state.join("0000:01:00.1").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn release_failed() {
+        let root = fake_tree("release-failed");
+        let paths = Paths::new(&root);
+        let pci = root.join("sys/bus/pci");
+        link(&pci, "0000:01:00.0", &pci.join("drivers/vfio-pci"));
+        fs::create_dir_all(&paths.state).unwrap();
+        fs::File::create(paths.state.join("0000:01:00.0")).unwrap().write_all(b"nouveau").unwrap();
+        let policy = Policy::parse("10de:1b80\n10de:10f0\n").unwrap();
+        let ubind = Ubind { paths: &paths, policy: &policy, dryrun: false, remove: true, quiet: true };
+
+        // nouveau isn't loaded, so it can't take the device after vfio-pci let go of it
+        fs::remove_file(pci.join("drivers/nouveau/bind")).unwrap();
+        let kernel = FakeKernel::start(&root);
+        let report = ubind.run("0000:01:00.0");
+        assert_eq!(kernel.stop(), vec!["vfio-pci/unbind 0000:01:00.0"]);
+        assert_eq!(report.error.unwrap(), "Failed to rebind 0000:01:00.0!");
+        assert_eq!(report.members[0], MemberReport {
+            slot: "0000:01:00.0".to_owned(),
+            driver_before: Some("vfio-pci".to_owned()),
+            driver_after: None,
+        });
+        assert!(!pci.join("devices/0000:01:00.0/driver").exists());
+        assert_eq!(read_trimmed(&paths.state.join("0000:01:00.0")).unwrap(), "nouveau");
+
+        // so the next attempt still knows where it goes, even though it has no driver now
+        fs::File::create(pci.join("drivers/nouveau/bind")).unwrap();
+        let kernel = FakeKernel::start(&root);
+        let report = ubind.run("0000:01:00.0");
+        assert_eq!(kernel.stop(), vec!["nouveau/bind 0000:01:00.0"]);
+        assert_eq!(report.error, None);
+        assert_eq!(report.members[0], MemberReport {
+            slot: "0000:01:00.0".to_owned(),
+            driver_before: None,
+            driver_after: Some("nouveau".to_owned()),
+        });
+        assert!(!paths.state.join("0000:01:00.0").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn slots() {
+        assert!(valid_slot("0000:01:00.0"));
+        assert!(valid_slot("0000:0a:1f.7"));
+        assert!(!valid_slot("01:00.0"));
+        assert!(!valid_slot("0000:01:00.0/../../.."));
+        assert!(!valid_slot("../../../../etc"));
     }
-    
-    pretty_write("/sys/bus/pci/drivers_probe", &device, dryrun);
 }
@@ -0,0 +1,86 @@
+//! Which devices the administrator allows us to take away from the host.
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Rule {
+    /// `0000:01:00.0`
+    Slot(String),
+    /// `10de:1b80`
+    Id(u16, u16),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+fn parse_rule(rule: &str) -> Option<Rule> {
+    let parts: Vec<_> = rule.split(':').collect();
+    match parts.len() {
+        2 => Some(Rule::Id(u16::from_str_radix(parts[0], 16).ok()?, u16::from_str_radix(parts[1], 16).ok()?)),
+        3 if rule.contains('.') => Some(Rule::Slot(rule.to_lowercase())),
+        _ => None,
+    }
+}
+
+impl Policy {
+    /// One slot or vendor:device id per line, `#` starts a comment
+    pub fn parse(text: &str) -> Result<Policy, String> {
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let rule = line.split('#').next().unwrap().trim();
+            if rule.is_empty() {
+                continue;
+            }
+            rules.push(parse_rule(rule).ok_or_else(|| format!("line {}: invalid entry `{}`", i + 1, rule))?);
+        }
+        Ok(Policy { rules })
+    }
+
+    /// Reads the policy, but only if nobody but root could have written it.
+    ///
+    /// The ownership check is skipped for fake trees, which are owned by whoever runs the tests.
+    pub fn load(path: &Path, root_owned: bool) -> Result<Policy, String> {
+        let mut file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let meta = file.metadata().map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+        if root_owned && (meta.uid() != 0 || meta.mode() & 0o022 != 0) {
+            return Err(format!("{} has to be owned by root and must not be writable by anyone else",
+                               path.display()));
+        }
+        let mut text = String::new();
+        file.read_to_string(&mut text).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Policy::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn allows(&self, slot: &str, vendor: u16, device: u16) -> bool {
+        self.rules.iter().any(|rule| match *rule {
+            Rule::Slot(ref x) => x.eq_ignore_ascii_case(slot),
+            Rule::Id(v, d) => v == vendor && d == device,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let policy = Policy::parse("# the GPU\n0000:01:00.0\n\n10DE:10f0 # its audio\n").unwrap();
+        assert_eq!(policy.rules, vec![Rule::Slot("0000:01:00.0".to_owned()), Rule::Id(0x10de, 0x10f0)]);
+        assert!(Policy::parse("0000:01:00.0\nnvme\n").is_err());
+        assert!(Policy::parse("10de:1b80:00").is_err());
+    }
+
+    #[test]
+    fn allows() {
+        let policy = Policy::parse("0000:01:00.0\n10de:10f0\n").unwrap();
+        assert!(policy.allows("0000:01:00.0", 0x10de, 0x1b80));
+        assert!(policy.allows("0000:02:00.1", 0x10de, 0x10f0));
+        assert!(!policy.allows("0000:03:00.0", 0x144d, 0xa808));
+    }
+}
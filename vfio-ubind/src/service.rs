@@ -0,0 +1,250 @@
+//! `org.windowsgaming.Vfio1` on the system bus, so the driver doesn't need the setuid binary.
+//!
+//! The service is started by D-Bus activation and runs as root. Instead of the vfio group,
+//! polkit decides who may bind and release devices.
+
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use dbus::{Connection, ConnectionItem, BusType, Message, MessageItem, NameFlag, RequestNameReply};
+use dbus::tree::{Factory, MethodErr};
+use serde_json;
+
+use policy::Policy;
+use {Paths, Ubind, Member, valid_slot, syslog};
+
+pub const NAME: &str = "org.windowsgaming.Vfio1";
+pub const PATH: &str = "/org/windowsgaming/Vfio1";
+pub const INTERFACE: &str = "org.windowsgaming.Vfio1";
+
+const ACTION_BIND: &str = "org.windowsgaming.vfio.bind";
+const ACTION_UNBIND: &str = "org.windowsgaming.vfio.unbind";
+
+/// Nobody needs us once the driver has its devices, D-Bus starts us again when it does
+const IDLE_TIMEOUT: u64 = 30;
+
+/// Asks polkit whether the sender of `msg` may do `action`, letting it ask for a password if needed.
+fn authorized(conn: &Connection, msg: &Message, action: &str) -> Result<bool, String> {
+    let sender = msg.sender().ok_or("message without sender")?;
+    let subject = MessageItem::Struct(vec![
+        "system-bus-name".into(),
+        MessageItem::new_array(vec![MessageItem::DictEntry(
+            Box::new("name".into()),
+            Box::new(MessageItem::Variant(Box::new(sender.to_string().into()))),
+        )]).unwrap(),
+    ]);
+    let mut check = Message::new_method_call("org.freedesktop.PolicyKit1",
+                                             "/org/freedesktop/PolicyKit1/Authority",
+                                             "org.freedesktop.PolicyKit1.Authority",
+                                             "CheckAuthorization").unwrap();
+    check.append_items(&[subject,
+                         action.into(),
+                         MessageItem::Array(Vec::new(), "a{ss}".into()),
+                         // AllowUserInteraction
+                         1u32.into(),
+                         "".into()]);
+    // the user may have to type their password, so this takes a while
+    let reply = conn.send_with_reply_and_block(check, 120_000)
+        .map_err(|e| format!("polkit: {}", e.message().unwrap_or("no reply")))?;
+    match reply.get_items().get(0) {
+        Some(&MessageItem::Struct(ref result)) => match result.get(0) {
+            Some(&MessageItem::Bool(x)) => Ok(x),
+            _ => Err("polkit: invalid reply".to_owned()),
+        },
+        _ => Err("polkit: invalid reply".to_owned()),
+    }
+}
+
+/// BindVfio and UnbindVfio, replying with the same JSON report `--json` prints
+fn rebind(conn: &Connection, paths: &Paths, fake_root: bool, msg: &Message, remove: bool)
+          -> Result<Message, MethodErr> {
+    let devices: Vec<String> = msg.get1().ok_or_else(MethodErr::no_arg)?;
+    let sender = msg.sender().map(|x| x.to_string()).unwrap_or_default();
+    syslog(&format!("{} requested to {} {} over D-Bus", sender, if remove { "release" } else { "bind" },
+                    devices.join(", ")));
+
+    // polkit doesn't know about fake trees, and only their owner can get us to touch them anyway
+    if !fake_root {
+        let action = if remove { ACTION_UNBIND } else { ACTION_BIND };
+        match authorized(conn, msg, action) {
+            Ok(true) => (),
+            Ok(false) => {
+                syslog(&format!("refused {}: not authorized for {}", sender, action));
+                return Err(("org.freedesktop.DBus.Error.AccessDenied", format!("Not authorized for {}", action))
+                    .into());
+            }
+            Err(e) => {
+                syslog(&format!("refused {}: {}", sender, e));
+                return Err(MethodErr::failed(&e));
+            }
+        }
+    }
+
+    let policy = Policy::load(&paths.policy, !fake_root).map_err(|e| {
+        syslog(&format!("refused {}: {}", devices.join(", "), e));
+        MethodErr::failed(&e)
+    })?;
+    let ubind = Ubind { paths, policy: &policy, dryrun: false, remove, quiet: true };
+    let report = serde_json::to_string(&ubind.batch(&devices)).unwrap();
+    Ok(msg.method_return().append1(report))
+}
+
+/// Every device in the group of `device` (bridges included) and its driver, or "" if it has none
+fn group_status(paths: &Paths, msg: &Message) -> Result<Message, MethodErr> {
+    let device: String = msg.get1().ok_or_else(MethodErr::no_arg)?;
+    if !valid_slot(&device) {
+        return Err(MethodErr::invalid_arg(&device));
+    }
+    let group = paths.pci.join("devices").join(&device).join("iommu_group").join("devices");
+    let entries = group.read_dir().map_err(|e| MethodErr::failed(&format!("{}: {}", device, e)))?;
+    let mut members: Vec<_> = entries.filter_map(Result::ok)
+        .map(|x| Member { slot: x.file_name().to_string_lossy().into_owned(), sysfs: x.path() })
+        .collect();
+    members.sort_by(|a, b| a.slot.cmp(&b.slot));
+    let status = members.iter()
+        .map(|x| MessageItem::Struct(vec![x.slot.as_str().into(), x.driver().unwrap_or_default().into()]))
+        .collect();
+    let status = MessageItem::new_array(status).map_err(|_| MethodErr::failed(&"empty IOMMU group"))?;
+    Ok(msg.method_return().append1(status))
+}
+
+/// Serves requests until nobody wanted anything for a while.
+pub fn serve(paths: Paths, fake_root: bool) -> Result<(), String> {
+    let conn = Rc::new(Connection::get_private(BusType::System)
+        .map_err(|e| format!("Failed to connect to the system bus: {:?}", e))?);
+    match conn.register_name(NAME, NameFlag::DoNotQueue as u32) {
+        Ok(RequestNameReply::PrimaryOwner) => (),
+        x => return Err(format!("Failed to acquire D-Bus name {}: {:?}", NAME, x)),
+    }
+
+    let paths = Rc::new(paths);
+    let f = Factory::new_fn::<()>();
+    let (c1, c2) = (conn.clone(), conn.clone());
+    let (p1, p2, p3) = (paths.clone(), paths.clone(), paths.clone());
+    let iface = f.interface(INTERFACE, ())
+        .add_m(f.method("BindVfio", (), move |m| Ok(vec![rebind(&c1, &p1, fake_root, m.msg, false)?]))
+            .in_arg(("devices", "as"))
+            .out_arg(("report", "s")))
+        .add_m(f.method("UnbindVfio", (), move |m| Ok(vec![rebind(&c2, &p2, fake_root, m.msg, true)?]))
+            .in_arg(("devices", "as"))
+            .out_arg(("report", "s")))
+        .add_m(f.method("GroupStatus", (), move |m| Ok(vec![group_status(&p3, m.msg)?]))
+            .in_arg(("device", "s"))
+            .out_arg(("members", "a(ss)")));
+    let tree = f.tree(()).add(f.object_path(PATH, ()).introspectable().add(iface));
+    tree.set_registered(&conn, true).map_err(|e| format!("Failed to register D-Bus object: {:?}", e))?;
+    debug!("Serving {}", NAME);
+
+    let mut last_call = Instant::now();
+    for item in conn.iter(1000) {
+        match item {
+            ConnectionItem::MethodCall(ref msg) => {
+                last_call = Instant::now();
+                if let Some(replies) = tree.handle(msg) {
+                    for reply in replies {
+                        let _ = conn.send(reply);
+                    }
+                }
+            }
+            _ if last_call.elapsed() >= Duration::from_secs(IDLE_TIMEOUT) => break,
+            _ => (),
+        }
+    }
+    debug!("Idle for {}s, exiting", IDLE_TIMEOUT);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::{Command, Child, Stdio};
+    use serde_json::Value;
+    use test::fake_tree;
+
+    /// A dbus-daemon standing in for the system bus, with a config that lets us own our name
+    struct PrivateBus(Child);
+
+    impl PrivateBus {
+        fn start() -> Option<PrivateBus> {
+            let mut daemon = match Command::new("dbus-daemon").args(&["--session", "--nofork", "--print-address"])
+                    .stdout(Stdio::piped()).spawn() {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("Skipping, can't start dbus-daemon: {}", e);
+                    return None;
+                }
+            };
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            env::set_var("DBUS_SYSTEM_BUS_ADDRESS", address.trim());
+            Some(PrivateBus(daemon))
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Calls `method`, giving the service a moment to show up on the bus
+    fn call(bus: &Connection, method: &str, arg: MessageItem) -> Result<Message, String> {
+        let start = Instant::now();
+        loop {
+            let mut msg = Message::new_method_call(NAME, PATH, INTERFACE, method).unwrap();
+            msg.append_items(&[arg.clone()]);
+            match bus.send_with_reply_and_block(msg, 5000) {
+                Err(ref e) if e.name() == Some("org.freedesktop.DBus.Error.ServiceUnknown")
+                    && start.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(100)),
+                res => return res.map_err(|e| e.name().unwrap_or("").to_owned()),
+            }
+        }
+    }
+
+    fn report(reply: Message) -> Value {
+        serde_json::from_str(reply.get1::<&str>().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn methods() {
+        let _daemon = match PrivateBus::start() {
+            Some(x) => x,
+            None => return,
+        };
+        let root = fake_tree("service");
+        fs::create_dir_all(root.join("etc/windows-gaming")).unwrap();
+        fs::File::create(root.join("etc/windows-gaming/vfio-ubind.conf")).unwrap()
+            .write_all(b"10de:1b80\n10de:10f0\n").unwrap();
+        let paths = Paths::new(&root);
+        // exits on its own once we're done and it's idle
+        thread::spawn(move || serve(paths, true).unwrap());
+
+        let bus = Connection::get_private(BusType::System).unwrap();
+        let status = call(&bus, "GroupStatus", "0000:01:00.0".into()).unwrap();
+        let member = |slot: &str, driver: &str| MessageItem::Struct(vec![slot.into(), driver.into()]);
+        assert_eq!(status.get_items(), vec![MessageItem::new_array(vec![
+            member("0000:00:01.0", "pcieport"),
+            member("0000:01:00.0", "nouveau"),
+            member("0000:01:00.1", "snd_hda_intel"),
+        ]).unwrap()]);
+        assert_eq!(call(&bus, "GroupStatus", "../../../etc".into()).unwrap_err(),
+                   "org.freedesktop.DBus.Error.InvalidArgs");
+
+        let devices = |slots: &[&str]| MessageItem::new_array(slots.iter().map(|&x| x.into()).collect()).unwrap();
+        // nothing of the group is bound to vfio-pci, so there's nothing to give back
+        let unbind = report(call(&bus, "UnbindVfio", devices(&["0000:01:00.0"])).unwrap());
+        assert_eq!(unbind["remove"], Value::Bool(true));
+        assert_eq!(unbind["devices"][0]["error"], Value::Null);
+        assert_eq!(unbind["devices"][0]["members"][1]["driver_after"], Value::String("snd_hda_intel".to_owned()));
+
+        let bind = report(call(&bus, "BindVfio", devices(&["0000:02:00.0"])).unwrap());
+        assert_eq!(bind["remove"], Value::Bool(false));
+        assert!(bind["devices"][0]["error"].as_str().unwrap().contains("does it exist?"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -2,8 +2,10 @@ use std::path::Path;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::collections::BTreeMap;
 
 use hotkeys::{KeyBinding, Key, Modifier};
+use util;
 
 use toml;
 use serde_yaml;
@@ -99,9 +101,111 @@ pub struct Config {
     pub sound: Option<SoundConfig>,
     pub samba: Option<SambaConfig>,
     pub setup: Option<SetupConfig>,
-    pub additional_qemu_cmdline: Option<String>,
+    pub additional_qemu_cmdline: Option<QemuArgs>,
+    #[serde(default)]
+    pub extra_objects: Vec<QemuObject>,
+    #[serde(default)]
+    pub extra_devices: Vec<QemuDevice>,
+    #[serde(default)]
+    pub hooks: Hooks,
     pub runtime_directory_override: Option<String>,
     pub data_directory_override: Option<String>,
+    pub state_directory_override: Option<String>,
+}
+
+/// Raw arguments appended to the QEMU command line
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum QemuArgs {
+    List(Vec<String>),
+    /// Split with shell quoting rules
+    Shell(String),
+}
+
+impl QemuArgs {
+    pub fn to_args(&self) -> Result<Vec<String>, String> {
+        match *self {
+            QemuArgs::List(ref x) => Ok(x.clone()),
+            QemuArgs::Shell(ref x) => util::split_shell_words(x),
+        }
+    }
+}
+
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 60;
+
+/// Commands run on the host around each VM session
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Hooks {
+    /// Run in order before anything else happens, the first failure aborts the start
+    #[serde(default)]
+    pub pre_start: Vec<Hook>,
+    /// Run in order once QEMU is gone, or starting it failed.
+    /// Those named like a pre_start hook only run if that one completed.
+    #[serde(default)]
+    pub post_stop: Vec<Hook>,
+    /// Run in the background while the VM is up, whenever their event happens
+    #[serde(default)]
+    pub events: Vec<EventHook>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Hook {
+    pub name: Option<String>,
+    /// Program and arguments, not run through a shell
+    pub command: Vec<String>,
+    /// Seconds until the hook gets killed, see `timeout()` for the default
+    pub timeout: Option<u64>,
+}
+
+impl Hook {
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// All devices went to Windows
+    FullEntry,
+    /// Only keyboard and mouse went to Windows
+    LightEntry,
+    /// Everything is back on the host
+    Detach,
+    /// The guest agent (re)started
+    GaUp,
+    /// The guest agent stopped answering pings
+    GaDown,
+    Suspend,
+    Resume,
+    /// Linux or Windows took the clipboard
+    ClipboardGrab,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct EventHook {
+    pub on: HookEvent,
+    #[serde(flatten)]
+    pub hook: Hook,
+}
+
+/// An additional `-object type,id=...,key=value`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct QemuObject {
+    #[serde(rename = "type")]
+    pub qom_type: String,
+    pub id: String,
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+}
+
+/// An additional `-device driver,id=...,key=value`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct QemuDevice {
+    pub driver: String,
+    pub id: Option<String>,
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
 }
 
 #[serde(default)]
@@ -214,11 +318,25 @@ pub struct SetupConfig {
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct MachineConfig {
+    // emulator settings, see the accessors below for the defaults
+    pub emulator: Option<String>,
+    pub machine_type: Option<String>,
+    pub machine_options: Option<Vec<String>>,
+    pub cpu_model: Option<String>,
+    pub hyperv: Option<Vec<String>>,
+    pub cpu_flags: Option<Vec<String>>,
+
     pub memory: String,
-    pub hugepages: Option<bool>,
+    pub hugepages: Option<Hugepages>,
 
+    // -smp topology, cores and threads are per die
+    pub sockets: Option<u32>,
+    pub dies: Option<u32>,
     pub cores: usize,
     pub threads: Option<u32>,
+    pub cpu_pinning: Option<CpuPinning>,
+    #[serde(default)]
+    pub numa_nodes: Vec<NumaNode>,
 
     #[serde(default)]
     pub light_mouse_speed: f64,
@@ -230,6 +348,172 @@ pub struct MachineConfig {
     pub usb_devices: Vec<UsbDevice>,
     #[serde(default = "machineconfig_hotkeys_default")]
     pub hotkeys: Vec<HotKey>,
+
+    /// Seconds Windows gets to shut down before we stop asking nicely, see `shutdown_timeout()`
+    pub shutdown_timeout: Option<u64>,
+}
+
+pub const DEFAULT_EMULATOR: &str = "/usr/bin/qemu-system-x86_64";
+pub const DEFAULT_MACHINE_TYPE: &str = "pc-q35-3.1";
+pub const DEFAULT_MACHINE_OPTIONS: &[&str] = &["kernel-irqchip=on"];
+pub const DEFAULT_CPU_MODEL: &str = "host";
+pub const DEFAULT_HYPERV: &[&str] = &[
+    "hv_time",
+    "hv_relaxed",
+    "hv_vapic",
+    "hv_spinlocks=0x1fff",
+    "hv_vendor_id=NvidiaFuckU",
+];
+// hide the hypervisor from the NVIDIA driver
+pub const DEFAULT_CPU_FLAGS: &[&str] = &["kvm=off"];
+/// Windows updates can take their time, but not forever
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 60;
+
+fn or_default<'a>(list: &'a Option<Vec<String>>, default: &'a [&'a str]) -> Vec<&'a str> {
+    match *list {
+        Some(ref x) => x.iter().map(|x| x.as_str()).collect(),
+        None => default.to_vec(),
+    }
+}
+
+impl MachineConfig {
+    /// Path to the qemu-system binary
+    pub fn emulator(&self) -> &str {
+        self.emulator.as_ref().map(|x| x.as_str()).unwrap_or(DEFAULT_EMULATOR)
+    }
+
+    pub fn machine_type(&self) -> &str {
+        self.machine_type.as_ref().map(|x| x.as_str()).unwrap_or(DEFAULT_MACHINE_TYPE)
+    }
+
+    /// Additional `-machine` options like `kernel-irqchip=on`
+    pub fn machine_options(&self) -> Vec<&str> {
+        or_default(&self.machine_options, DEFAULT_MACHINE_OPTIONS)
+    }
+
+    pub fn cpu_model(&self) -> &str {
+        self.cpu_model.as_ref().map(|x| x.as_str()).unwrap_or(DEFAULT_CPU_MODEL)
+    }
+
+    /// Hyper-V enlightenments like `hv_relaxed` or `hv_spinlocks=0x1fff`
+    pub fn hyperv(&self) -> Vec<&str> {
+        or_default(&self.hyperv, DEFAULT_HYPERV)
+    }
+
+    /// Other `-cpu` flags like `kvm=off` or `+invtsc`
+    pub fn cpu_flags(&self) -> Vec<&str> {
+        or_default(&self.cpu_flags, DEFAULT_CPU_FLAGS)
+    }
+
+    /// How long the guest agent and then ACPI each get to shut Windows down before we escalate
+    pub fn shutdown_timeout(&self) -> u64 {
+        self.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)
+    }
+
+    /// Guest memory in bytes, `None` if `memory` can't be parsed
+    pub fn memory_bytes(&self) -> Option<u64> {
+        util::parse_size(&self.memory, 1 << 20)
+    }
+
+    /// Hugepage settings, if guest memory should be backed by hugepages
+    pub fn hugepages(&self) -> Option<HugepageConfig> {
+        match self.hugepages {
+            Some(Hugepages::Enabled(true)) => Some(HugepageConfig {
+                size: PageSize::Size1G,
+                mount: None,
+                numa_node: None,
+            }),
+            Some(Hugepages::Enabled(false)) | None => None,
+            Some(Hugepages::Config(ref x)) => Some(x.clone()),
+        }
+    }
+
+    /// Number of vCPUs the `-smp` topology results in
+    pub fn vcpus(&self) -> usize {
+        self.sockets.unwrap_or(1) as usize * self.dies.unwrap_or(1) as usize * self.cores
+            * self.threads.unwrap_or(1) as usize
+    }
+}
+
+/// `hugepages = true` is still supported and means 1G pages at the old mount point
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Hugepages {
+    Enabled(bool),
+    Config(HugepageConfig),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    #[serde(rename = "2M")] Size2M,
+    #[serde(rename = "1G")] Size1G,
+}
+
+impl PageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size2M => 2 << 20,
+            PageSize::Size1G => 1 << 30,
+        }
+    }
+}
+
+impl Display for PageSize {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            PageSize::Size2M => f.write_str("2M"),
+            PageSize::Size1G => f.write_str("1G"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HugepageConfig {
+    pub size: PageSize,
+    /// hugetlbfs mount with the same page size, see `mount()` for the default
+    pub mount: Option<String>,
+    /// Host NUMA node to take the pages from
+    pub numa_node: Option<u32>,
+}
+
+impl HugepageConfig {
+    pub fn mount(&self) -> &str {
+        match (&self.mount, self.size) {
+            (&Some(ref x), _) => x,
+            (&None, PageSize::Size2M) => "/dev/hugepages",
+            (&None, PageSize::Size1G) => "/dev/hugepages_vfio_1G",
+        }
+    }
+}
+
+/// A guest NUMA node
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NumaNode {
+    /// vCPUs (in -smp order) belonging to this node
+    pub vcpus: Vec<usize>,
+    /// Memory of this node like `8G`, all nodes together have to add up to `memory`
+    pub memory: String,
+    /// Host node the memory is bound to
+    pub host_node: Option<u32>,
+}
+
+impl NumaNode {
+    pub fn memory_bytes(&self) -> Option<u64> {
+        util::parse_size(&self.memory, 1 << 20)
+    }
+}
+
+/// Which host CPUs QEMU's threads may run on
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CpuPinning {
+    /// Host CPU of each vCPU, in the order QEMU numbers them (threads of a core are adjacent)
+    pub vcpus: Vec<usize>,
+    /// Host CPUs for everything else QEMU runs (main loop, IO threads, workers).
+    /// Empty leaves them alone.
+    #[serde(default)]
+    pub emulator: Vec<usize>,
+    /// Run vCPU threads as SCHED_FIFO with this priority (1-99)
+    pub fifo_priority: Option<i32>,
 }
 
 fn machineconfig_hotkeys_default() -> Vec<HotKey> {
@@ -257,6 +541,29 @@ pub struct VfioDevice {
     pub resettable: bool,
     pub slot: String,
     pub id: PciId,
+    #[serde(flatten)]
+    pub options: VfioOptions,
+}
+
+/// How a passed through device is presented to the guest
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct VfioOptions {
+    /// ROM image to use instead of the one on the device, e.g. a patched VBIOS
+    pub romfile: Option<String>,
+    /// Whether the guest sees the option ROM at all
+    pub rombar: Option<bool>,
+    /// Legacy VGA regions, defaults to on for the primary display
+    pub x_vga: Option<bool>,
+    /// Guest bus like `pcie.0` or the id of a root port
+    pub bus: Option<String>,
+    /// Guest address on that bus like `05.0`
+    pub addr: Option<String>,
+    /// Subsystem ids to report, some drivers insist on certain vendors
+    pub sub_vendor_id: Option<u16>,
+    pub sub_device_id: Option<u16>,
+    /// The GPU the guest boots on
+    #[serde(default)]
+    pub primary_display: bool,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -22,3 +22,162 @@ pub fn usable_ports(bus: UsbBus) -> usize {
         UsbBus::Xhci => 15,
     }
 }
+
+/// Splits a string into arguments like a POSIX shell would, minus expansions.
+///
+/// Supports single quotes, double quotes (with `\"`, `\\`, `\$` and `` \` `` escapes) and
+/// backslash escapes outside of quotes.
+pub fn split_shell_words(s: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = None::<String>;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if let Some(w) = word.take() {
+                    words.push(w);
+                }
+            }
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => w.push(c),
+                        None => return Err(format!("unterminated single quote in `{}`", s)),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') | Some(c @ '`') => w.push(c),
+                            Some('\n') => (),
+                            Some(c) => {
+                                w.push('\\');
+                                w.push(c);
+                            }
+                            None => return Err(format!("unterminated double quote in `{}`", s)),
+                        },
+                        Some(c) => w.push(c),
+                        None => return Err(format!("unterminated double quote in `{}`", s)),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => (),
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(format!("trailing backslash in `{}`", s)),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    words.extend(word);
+    Ok(words)
+}
+
+/// Parses sizes like `8G` or `512M` into bytes. Plain numbers are in `default_unit` bytes,
+/// which for QEMU's `-m` is a MiB.
+pub fn parse_size(s: &str, default_unit: u64) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_digit(10)).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let unit = match &*suffix.to_uppercase() {
+        "" => default_unit,
+        "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Parses the kernel's cpu list format, e.g. `0-3,8,10-11`
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',').filter(|x| !x.is_empty()) {
+        let mut bounds = part.splitn(2, '-');
+        let start: usize = bounds.next()?.parse().ok()?;
+        let end: usize = match bounds.next() {
+            Some(x) => x.parse().ok()?,
+            None => start,
+        };
+        if end < start {
+            return None;
+        }
+        cpus.extend(start..end + 1);
+    }
+    Some(cpus)
+}
+
+/// The reverse of `parse_cpu_list`, one `a-b` (or just `a`) per contiguous range
+pub fn cpu_ranges(cpus: &[usize]) -> Vec<String> {
+    let mut cpus = cpus.to_vec();
+    cpus.sort();
+    cpus.dedup();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for cpu in cpus {
+        match ranges.last_mut() {
+            Some(&mut (_, ref mut end)) if *end + 1 == cpu => *end = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    ranges.into_iter()
+        .map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn split(s: &str) -> Vec<String> {
+        split_shell_words(s).unwrap()
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(split("-smp  4 "), vec!["-smp", "4"]);
+        assert_eq!(split(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(split(r#"-drive 'file=/my disks/a.img' -smbios "type=1,product=\"Foo\"""#),
+                   vec!["-drive", "file=/my disks/a.img", "-smbios", r#"type=1,product="Foo""#]);
+        assert_eq!(split(r#"a''b "" c\ d"#), vec!["ab", "", "c d"]);
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("8G", 1 << 20), Some(8 << 30));
+        assert_eq!(parse_size("512m", 1 << 20), Some(512 << 20));
+        assert_eq!(parse_size("4096", 1 << 20), Some(4 << 30));
+        assert_eq!(parse_size("1024M", 1), Some(1 << 30));
+        assert_eq!(parse_size("8 apples", 1), None);
+        assert_eq!(parse_size("G", 1), None);
+    }
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpu_list("0\n"), Some(vec![0]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a-b"), None);
+        assert_eq!(cpu_ranges(&[11, 0, 1, 2, 3, 8, 10]), vec!["0-3", "8", "10-11"]);
+    }
+
+    #[test]
+    fn unterminated() {
+        assert!(split_shell_words("-name 'foo").is_err());
+        assert!(split_shell_words("-name \"foo").is_err());
+        assert!(split_shell_words("foo\\").is_err());
+    }
+}
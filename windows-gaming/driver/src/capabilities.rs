@@ -0,0 +1,234 @@
+use std::process::{Command, Stdio};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use common::config::{Config, UsbBus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct QemuVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+}
+
+impl QemuVersion {
+    pub fn new(major: u32, minor: u32, micro: u32) -> QemuVersion {
+        QemuVersion { major, minor, micro }
+    }
+}
+
+impl Display for QemuVersion {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+    }
+}
+
+/// QEMU versions starting with this one configure the hda codec through `-device`
+/// (with an `audiodev` property) instead of the now removed `-soundhw hda`
+pub const HDA_DEVICE_VERSION: QemuVersion = QemuVersion { major: 4, minor: 2, micro: 0 };
+/// First QEMU version with `-audiodev`
+pub const AUDIODEV_VERSION: QemuVersion = QemuVersion { major: 4, minor: 0, micro: 0 };
+
+/// First QEMU version that knows about dies in `-smp`
+pub const DIES_VERSION: QemuVersion = QemuVersion { major: 4, minor: 1, micro: 0 };
+
+/// QMP commands the controller can't do without
+pub const REQUIRED_QMP_COMMANDS: &[&str] = &[
+    "device_add",
+    "device_del",
+    "input-send-event",
+    "quit",
+    "system_powerdown",
+    "system_wakeup",
+];
+
+/// What the installed QEMU is able to do
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub version: QemuVersion,
+    devices: HashSet<String>,
+    machines: HashSet<String>,
+}
+
+fn run_help(emulator: &str, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(emulator).args(args).stdin(Stdio::null()).output()
+        .map_err(|e| format!("Failed to run {}: {}", emulator, e))?;
+    if !output.status.success() {
+        return Err(format!("`{} {}` failed with {}", emulator, args.join(" "), output.status));
+    }
+    // older versions print some of these to stderr
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(text)
+}
+
+impl Capabilities {
+    pub fn new(version: QemuVersion, devices: &[&str], machines: &[&str]) -> Capabilities {
+        Capabilities {
+            version,
+            devices: devices.iter().map(|x| x.to_string()).collect(),
+            machines: machines.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    /// Asks the given QEMU binary about its version, devices and machine types.
+    pub fn probe(emulator: &str) -> Result<Capabilities, String> {
+        let version = run_help(emulator, &["-version"])?;
+        let version = parse_version(&version)
+            .ok_or_else(|| format!("Can't make sense of the version {} reports: {}", emulator, version.trim()))?;
+        let caps = Capabilities {
+            version,
+            devices: parse_devices(&run_help(emulator, &["-device", "help"])?),
+            machines: parse_machines(&run_help(emulator, &["-machine", "help"])?),
+        };
+        debug!("QEMU {} with {} devices and {} machine types", caps.version, caps.devices.len(),
+               caps.machines.len());
+        Ok(caps)
+    }
+
+    pub fn has_device(&self, name: &str) -> bool {
+        self.devices.contains(name)
+    }
+
+    pub fn has_machine(&self, name: &str) -> bool {
+        self.machines.contains(name)
+    }
+
+    pub fn at_least(&self, version: QemuVersion) -> bool {
+        self.version >= version
+    }
+
+    /// Whether the sound card has to be created with `-device` rather than `-soundhw`
+    pub fn hda_as_device(&self) -> bool {
+        self.at_least(HDA_DEVICE_VERSION)
+    }
+
+    /// Makes sure QEMU supports everything the command line for this config will need.
+    pub fn check(&self, cfg: &Config) -> Result<(), String> {
+        let machine = &cfg.machine;
+        let mut missing = Vec::new();
+
+        if !self.has_machine(machine.machine_type()) {
+            let mut q35: Vec<_> = self.machines.iter().filter(|x| x.contains("q35")).cloned().collect();
+            q35.sort();
+            missing.push(format!("machine type {} (available q35 types: {})", machine.machine_type(),
+                                 q35.join(", ")));
+        }
+
+        let mut devices = vec!["virtio-scsi-pci", "scsi-cd", "scsi-hd", "e1000", "qemu-xhci",
+                               "usb-mouse", "usb-kbd"];
+        if !machine.pci_devices.is_empty() {
+            devices.push("vfio-pci");
+        }
+        if !machine.usb_devices.is_empty() {
+            devices.push("usb-host");
+        }
+        for dev in &machine.usb_devices {
+            devices.push(match dev.bus {
+                UsbBus::Ohci => "pci-ohci",
+                UsbBus::Uhci => "ich9-usb-uhci1",
+                UsbBus::Ehci => "ich9-usb-ehci1",
+                UsbBus::Xhci => "qemu-xhci",
+            });
+        }
+        if self.hda_as_device() {
+            devices.extend(&["ich9-intel-hda", "hda-duplex"]);
+        }
+        devices.extend(cfg.extra_devices.iter().map(|x| &*x.driver));
+        devices.sort();
+        devices.dedup();
+        missing.extend(devices.into_iter().filter(|x| !self.has_device(x)).map(|x| format!("device {}", x)));
+
+        if machine.dies.is_some() && !self.at_least(DIES_VERSION) {
+            missing.push(format!("-smp dies= (needs QEMU {} or newer)", DIES_VERSION));
+        }
+        if cfg.sound.is_some() && !self.at_least(AUDIODEV_VERSION) {
+            missing.push(format!("-audiodev (needs QEMU {} or newer)", AUDIODEV_VERSION));
+        }
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("QEMU {} at {} is missing required features:\n\t{}", self.version,
+                        machine.emulator(), missing.join("\n\t")))
+        }
+    }
+}
+
+/// Parses `QEMU emulator version 4.2.1 (Debian 1:4.2-3ubuntu6)`
+fn parse_version(output: &str) -> Option<QemuVersion> {
+    let start = output.find("version ")? + "version ".len();
+    let mut parts = output[start..].split(|c: char| !c.is_digit(10)).take(3).map(|x| x.parse().ok());
+    Some(QemuVersion {
+        major: parts.next()??,
+        minor: parts.next()??,
+        micro: parts.next().and_then(|x| x).unwrap_or(0),
+    })
+}
+
+/// Collects names and aliases from lines like `name "virtio-scsi-pci", bus PCI, alias "virtio-scsi"`
+fn parse_devices(output: &str) -> HashSet<String> {
+    let mut devices = HashSet::new();
+    for line in output.lines() {
+        for key in &["name \"", "alias \""] {
+            if let Some(start) = line.find(key) {
+                let rest = &line[start + key.len()..];
+                if let Some(end) = rest.find('"') {
+                    devices.insert(rest[..end].to_owned());
+                }
+            }
+        }
+    }
+    devices
+}
+
+/// Collects the first column of everything after `Supported machines are:`
+fn parse_machines(output: &str) -> HashSet<String> {
+    output.lines().skip_while(|x| !x.starts_with("Supported machines"))
+        .skip(1)
+        .filter_map(|x| x.split_whitespace().next())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn version() {
+        assert_eq!(parse_version("QEMU emulator version 4.2.1 (Debian 1:4.2-3ubuntu6)\n\
+                                  Copyright (c) 2003-2019 Fabrice Bellard and the QEMU Project developers"),
+                   Some(QemuVersion::new(4, 2, 1)));
+        assert_eq!(parse_version("QEMU emulator version 3.1.0\n"), Some(QemuVersion::new(3, 1, 0)));
+        assert_eq!(parse_version("QEMU emulator version 8.2\n"), Some(QemuVersion::new(8, 2, 0)));
+        assert_eq!(parse_version("qemu-system-x86_64: -version: invalid option"), None);
+    }
+
+    #[test]
+    fn devices() {
+        let devices = parse_devices("Controller/Bridge/Hub devices:\n\
+            name \"qemu-xhci\", bus PCI\n\
+            \n\
+            Storage devices:\n\
+            name \"virtio-scsi-pci\", bus PCI, alias \"virtio-scsi\"\n\
+            name \"scsi-cd\", bus SCSI, desc \"virtual SCSI CD-ROM\"\n");
+        let mut devices: Vec<_> = devices.into_iter().collect();
+        devices.sort();
+        assert_eq!(devices, vec!["qemu-xhci", "scsi-cd", "virtio-scsi", "virtio-scsi-pci"]);
+    }
+
+    #[test]
+    fn machines() {
+        let machines = parse_machines("Supported machines are:\n\
+            microvm              microvm (i386)\n\
+            pc                   Standard PC (i440FX + PIIX, 1996) (alias of pc-i440fx-4.2)\n\
+            q35                  Standard PC (Q35 + ICH9, 2009) (alias of pc-q35-4.2)\n\
+            pc-q35-4.2           Standard PC (Q35 + ICH9, 2009) (default)\n\
+            none                 empty machine\n");
+        assert!(machines.contains("q35"));
+        assert!(machines.contains("pc-q35-4.2"));
+        assert!(!machines.contains("Supported"));
+        assert!(!machines.contains("pc-q35-3.1"));
+    }
+}
@@ -2,45 +2,87 @@ mod codec;
 
 pub use self::codec::{GaCmdOut, ClipboardMessage, ClipboardType, ClipboardTypes, RegisterHotKey, Point};
 
-use std::os::unix::net::{UnixStream as StdUnixStream};
+use std::os::unix::net::{UnixListener as StdUnixListener};
 use std::io::{Error, ErrorKind};
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::time::Duration;
+use std::mem;
 
-use futures::unsync::mpsc::{self, UnboundedSender};
+use futures::unsync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use futures::unsync::oneshot;
 use futures::{Stream, Sink, Future};
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Interval};
 use tokio_io::AsyncRead;
-use tokio_uds::UnixStream as TokioUnixStream;
-use tokio_timer::Timer;
+use tokio_uds::{UnixListener as TokioUnixListener, UnixStream as TokioUnixStream};
 
 use controller::Controller;
 use self::codec::{Codec, GaCmdIn};
 
 type Send = UnboundedSender<GaCmdOut>;
 type Sender = Box<Future<Item=(), Error=Error>>;
-type Read = Box<Stream<Item=GaCmdIn, Error=Error>>;
 type Handler<'a> = Box<Future<Item=(), Error=Error> + 'a>;
 
+/// The guest agent connection we currently talk to
+struct Connection {
+    id: u64,
+    send: UnboundedSender<GaCmdOut>,
+    // dropping this tears the connection down
+    _cancel: oneshot::Sender<()>,
+}
+
+/// Pings the GA periodically, restarted for every new GA instance.
+#[derive(Clone)]
+struct PingTimer {
+    // bumped on every (re)start so the old timer knows to stop
+    generation: Rc<Cell<u64>>,
+    controller: Rc<RefCell<Controller>>,
+    handle: Handle,
+}
+
+impl PingTimer {
+    fn start(&self) {
+        let generation = self.stop();
+        let current = self.generation.clone();
+        let controller = self.controller.clone();
+        let timer = Interval::new(Duration::new(5, 0), &self.handle).expect("Failed to create ping timer")
+            .map_err(|_| ())
+            .for_each(move |()| match current.get() == generation && controller.borrow_mut().ga_ping() {
+                true => Ok(()),
+                false => Err(()),
+            });
+        self.handle.spawn(timer);
+    }
+
+    /// Stops the running timer, returning the generation of the next one.
+    fn stop(&self) -> u64 {
+        let generation = self.generation.get() + 1;
+        self.generation.set(generation);
+        generation
+    }
+}
+
+/// Listens for guest agent connections.
+///
+/// QEMU's guestfwd opens a new connection whenever the GA connects, so after a GA restart
+/// or a Windows reboot the new connection replaces the old one.
 pub struct Clientpipe {
-    pub send: Option<Send>,
-    pub sender: Option<Sender>,
-    read: Option<Read>,
+    send: Option<Send>,
+    recv: Option<UnboundedReceiver<GaCmdOut>>,
+    listener: Option<TokioUnixListener>,
+    connection: Rc<RefCell<Option<Connection>>>,
 }
 
 impl Clientpipe {
-    pub fn new(stream: StdUnixStream, handle: &Handle) -> Clientpipe {
-        let stream = TokioUnixStream::from_stream(stream, &handle).unwrap();
-        let (write, read) = stream.framed(Codec).split();
+    pub fn new(listener: StdUnixListener, handle: &Handle) -> Clientpipe {
+        let listener = TokioUnixListener::from_listener(listener, &handle).unwrap();
         let (send, recv) = mpsc::unbounded();
-        let recv = recv.map_err(|()| Error::new(ErrorKind::Other, "Failed to write to clientpipe"));
-        let sender = write.send_all(recv).map(|_| ());
 
         Clientpipe {
             send: Some(send),
-            sender: Some(Box::new(sender)),
-            read: Some(Box::new(read)),
+            recv: Some(recv),
+            listener: Some(listener),
+            connection: Rc::new(RefCell::new(None)),
         }
     }
 
@@ -48,54 +90,269 @@ impl Clientpipe {
         self.send.take().unwrap()
     }
 
+    /// Forwards everything sent to the GA to the current connection
     pub fn take_sender(&mut self) -> Sender {
-        self.sender.take().unwrap()
+        let connection = self.connection.clone();
+        let sender = self.recv.take().unwrap().for_each(move |cmd| {
+            match *connection.borrow() {
+                Some(ref conn) => { let _ = (&conn.send).send(cmd); }
+                None => debug!("No guest agent connected, dropping {:?}", cmd),
+            }
+            Ok(())
+        }).map_err(|()| Error::new(ErrorKind::Other, "Failed to write to clientpipe"));
+        Box::new(sender)
     }
 
-    pub fn take_handler<'a>(&mut self, controller_rc: Rc<RefCell<Controller>>, handle: &'a Handle) -> Handler<'a> {
-        let handler = self.read.take().unwrap().for_each(move |cmd| {
-            trace!("GA sent message: {:?}", cmd);
-            let mut controller = controller_rc.borrow_mut();
-
-            match cmd {
-                GaCmdIn::ReportBoot(()) => {
-                    info!("client is now alive!");
-
-                    if controller.ga_hello() {
-                        let controller = controller_rc.clone();
-                        let timer = Timer::default().interval(Duration::new(5, 0))
-                            .map_err(|_| ())
-                            .for_each(move |()| match controller.borrow_mut().ga_ping() {
-                                true => Ok(()),
-                                false => Err(()),
-                            });
-                        handle.spawn(timer);
+    pub fn take_handler<'a>(&mut self, controller: Rc<RefCell<Controller>>, handle: &'a Handle) -> Handler<'a> {
+        let connection = self.connection.clone();
+        let ping = PingTimer {
+            generation: Rc::new(Cell::new(0)),
+            controller: controller.clone(),
+            handle: handle.clone(),
+        };
+        let mut next_id = 0;
+
+        let handler = self.listener.take().unwrap().incoming().for_each(move |(stream, _)| {
+            let id = next_id;
+            next_id += 1;
+
+            let (cancel, canceled) = oneshot::channel();
+            let (send, recv) = mpsc::unbounded();
+            let old = mem::replace(&mut *connection.borrow_mut(), Some(Connection { id, send, _cancel: cancel }));
+            if old.is_some() {
+                info!("Guest agent reconnected, dropping the old connection");
+            } else {
+                info!("Guest agent connected");
+            }
+            drop(old);
+            // pings sent to the old instance won't be answered, the new one starts over
+            ping.start();
+
+            let connection = connection.clone();
+            let ctrl = controller.clone();
+            let timer = ping.clone();
+            let conn = serve(stream, recv, controller.clone(), ping.clone())
+                .map_err(|e| warn!("Clientpipe connection failed: {}", e))
+                .select(canceled.then(|_| Ok(())))
+                .then(move |_| {
+                    let current = connection.borrow().as_ref().map(|x| x.id) == Some(id);
+                    // a replaced connection doesn't mean anything, the new one carries on
+                    if current {
+                        info!("Guest agent disconnected");
+                        *connection.borrow_mut() = None;
+                        timer.stop();
+                        ctrl.borrow_mut().ga_died();
                     }
-                }
-                GaCmdIn::Suspending(()) => {
-                    info!("client says that it's suspending");
-                    controller.ga_suspending();
-                }
-                GaCmdIn::Pong(()) => controller.ga_pong(),
-                GaCmdIn::HotKey(id) => controller.ga_hotkey(id),
-                GaCmdIn::HotKeyBindingFailed(s) => warn!("HotKeyBinding failed: {}", s),
-                GaCmdIn::Clipboard(c) => match c.message {
-                    Some(ClipboardMessage::GrabClipboard(())) => controller.grab_x11_clipboard(),
-                    Some(ClipboardMessage::RequestClipboardContents(kind)) => match ClipboardType::from_i32(kind) {
-                        Some(kind) => controller.read_x11_clipboard(kind),
-                        None => error!("Windows requested an invalid clipboard type??"),
-                    },
-                    Some(ClipboardMessage::ContentTypes(types)) => controller.respond_x11_types(types.types().collect()),
-                    Some(ClipboardMessage::ClipboardContents(buf)) => controller.respond_x11_clipboard(buf),
-                    None => error!("Windows sent an empty clipboard message??"),
+                    Ok(())
+                });
+            handle.spawn(conn);
+            Ok(())
+        });
+        Box::new(handler)
+    }
+}
+
+/// Reads and writes a single GA connection until it's closed
+fn serve(stream: TokioUnixStream, recv: UnboundedReceiver<GaCmdOut>, controller_rc: Rc<RefCell<Controller>>,
+         ping: PingTimer) -> Box<Future<Item=(), Error=Error>> {
+    let (write, read) = stream.framed(Codec).split();
+    let recv = recv.map_err(|()| Error::new(ErrorKind::Other, "Failed to write to clientpipe"));
+    let writer = write.send_all(recv).map(|_| ());
+
+    let reader = read.for_each(move |cmd| {
+        trace!("GA sent message: {:?}", cmd);
+        let mut controller = controller_rc.borrow_mut();
+
+        match cmd {
+            GaCmdIn::ReportBoot(()) => {
+                info!("client is now alive!");
+                controller.ga_hello();
+                // whatever pings are still in flight were meant for someone else
+                ping.start();
+            }
+            GaCmdIn::Suspending(()) => {
+                info!("client says that it's suspending");
+                controller.ga_suspending();
+            }
+            GaCmdIn::Pong(()) => controller.ga_pong(),
+            GaCmdIn::HotKey(id) => controller.ga_hotkey(id),
+            GaCmdIn::HotKeyBindingFailed(s) => warn!("HotKeyBinding failed: {}", s),
+            GaCmdIn::Clipboard(c) => match c.message {
+                Some(ClipboardMessage::GrabClipboard(())) => controller.grab_x11_clipboard(),
+                Some(ClipboardMessage::RequestClipboardContents(kind)) => match ClipboardType::from_i32(kind) {
+                    Some(kind) => controller.read_x11_clipboard(kind),
+                    None => error!("Windows requested an invalid clipboard type??"),
                 },
-                GaCmdIn::MouseEdged(Point { x, y }) => {
-                    trace!("Mouse Edged: {}:{}", x, y);
-                    controller.mouse_edged(x, y);
+                Some(ClipboardMessage::ContentTypes(types)) => controller.respond_x11_types(types.types().collect()),
+                Some(ClipboardMessage::ClipboardContents(buf)) => controller.respond_x11_clipboard(buf),
+                None => error!("Windows sent an empty clipboard message??"),
+            },
+            GaCmdIn::MouseEdged(Point { x, y }) => {
+                trace!("Mouse Edged: {}:{}", x, y);
+                controller.mouse_edged(x, y);
+            }
+        }
+        Ok(())
+    });
+    Box::new(reader.select(writer).map(|_| ()).map_err(|(e, _)| e))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate clientpipe_proto as proto;
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::thread;
+    use std::path::PathBuf;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc as std_mpsc;
+    use futures::future::Either;
+    use futures::sync::mpsc as sync_mpsc;
+    use futures::sync::oneshot as sync_oneshot;
+    use prost::Message;
+    use tokio_core::reactor::Core;
+    use control::ControlCmdOut;
+    use controller::{self, State};
+
+    fn hello(stream: &mut UnixStream) {
+        let mut buf = Vec::new();
+        proto::GaCmdIn { message: Some(GaCmdIn::ReportBoot(())) }.encode_length_delimited(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+    }
+
+    /// The GA side of a test, a thread with blocking sockets that waits for the driver instead of the clock
+    struct Ga {
+        path: PathBuf,
+        events: std_mpsc::Receiver<ControlCmdOut>,
+        ping: sync_mpsc::UnboundedSender<()>,
+    }
+
+    impl Ga {
+        /// Connects like QEMU's guestfwd would
+        fn connect(&self) -> UnixStream {
+            UnixStream::connect(&self.path).unwrap()
+        }
+
+        /// Waits until the controller emits `event`, skipping everything before it
+        fn wait_for(&self, event: ControlCmdOut) {
+            loop {
+                match self.events.recv_timeout(Duration::from_secs(5)) {
+                    Ok(ref x) if *x == event => return,
+                    Ok(_) => (),
+                    Err(e) => panic!("No {:?}: {}", event, e),
                 }
             }
+        }
+
+        /// Has the driver send a ping to whatever connection is current
+        fn ping(&self) {
+            self.ping.unbounded_send(()).unwrap();
+        }
+    }
+
+    /// Runs the clientpipe until the GA side is done.
+    ///
+    /// The GA side hands back its connections, they are only closed after we've seen all events.
+    fn run<F>(name: &str, ga: F) -> Vec<ControlCmdOut>
+        where F: FnOnce(&Ga) -> Vec<UnixStream> + ::std::marker::Send + 'static {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (controller, _fixture) = controller::test::controller(&handle);
+        let (events_send, events_recv) = mpsc::unbounded();
+        controller.borrow_mut().subscribe(events_send);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = events.clone();
+        let (ga_events, ga_events_recv) = std_mpsc::channel();
+        handle.spawn(events_recv.for_each(move |x| {
+            log.borrow_mut().push(x.clone());
+            let _ = ga_events.send(x);
+            Ok(())
+        }));
+
+        let path = env::temp_dir().join(format!("windows-gaming-clientpipe-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        let mut pipe = Clientpipe::new(StdUnixListener::bind(&path).unwrap(), &handle);
+        let send = pipe.take_send();
+        handle.spawn(pipe.take_sender().map_err(|_| ()));
+        let handler = pipe.take_handler(controller.clone(), &handle);
+
+        let (ping, pings) = sync_mpsc::unbounded();
+        handle.spawn(pings.for_each(move |()| {
+            let _ = (&send).send(GaCmdOut::Ping(()));
             Ok(())
+        }));
+
+        let (done, finished) = sync_oneshot::channel();
+        let ga_side = Ga { path: path.clone(), events: ga_events_recv, ping };
+        thread::spawn(move || {
+            let _ = done.send(ga(&ga_side));
         });
-        Box::new(handler)
+        let _connections = match core.run(handler.select2(finished)) {
+            Ok(Either::B((x, _))) => x,
+            _ => panic!("GA side failed"),
+        };
+        fs::remove_file(&path).unwrap();
+
+        let events = events.borrow().clone();
+        events
+    }
+
+    #[test]
+    fn reconnect() {
+        let events = run("reconnect", |ga| {
+            let mut first = ga.connect();
+            hello(&mut first);
+            ga.wait_for(ControlCmdOut::GaStateChanged { state: State::Up });
+            // the GA crashed
+            drop(first);
+            ga.wait_for(ControlCmdOut::GaDied);
+            let mut second = ga.connect();
+            hello(&mut second);
+            ga.wait_for(ControlCmdOut::GaStateChanged { state: State::Up });
+            vec![second]
+        });
+        assert_eq!(events, vec![
+            ControlCmdOut::GaHello,
+            ControlCmdOut::GaStateChanged { state: State::Up },
+            ControlCmdOut::GaStateChanged { state: State::Down },
+            ControlCmdOut::GaDied,
+            ControlCmdOut::GaHello,
+            ControlCmdOut::GaStateChanged { state: State::Up },
+        ]);
+    }
+
+    #[test]
+    fn replacement() {
+        let events = run("replacement", |ga| {
+            let mut first = ga.connect();
+            hello(&mut first);
+            ga.wait_for(ControlCmdOut::GaStateChanged { state: State::Up });
+            // Windows rebooted without the old connection being closed
+            let mut second = ga.connect();
+            // once the old one is hung up on, the new one is current
+            first.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(first.read(&mut [0; 16]).unwrap(), 0);
+
+            // only the new connection gets what we send to the GA
+            ga.ping();
+            second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut buf = [0; 16];
+            let len = second.read(&mut buf).unwrap();
+            let cmd = proto::GaCmdOut::decode_length_delimited(&buf[..len]).unwrap();
+            assert_eq!(cmd.message, Some(GaCmdOut::Ping(())));
+            hello(&mut second);
+            ga.wait_for(ControlCmdOut::GaHello);
+            vec![second]
+        });
+        // the old connection going away doesn't take the GA down
+        assert_eq!(events, vec![
+            ControlCmdOut::GaHello,
+            ControlCmdOut::GaStateChanged { state: State::Up },
+            ControlCmdOut::GaHello,
+        ]);
     }
 }
@@ -0,0 +1,767 @@
+//! Builds the QEMU command line. Everything in here is free of side effects, whatever
+//! needs to be looked up on the host is passed in as `HostFacts`.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::path::PathBuf;
+
+use itertools::Itertools;
+
+use common::config::{Config, MachineConfig, VfioDevice, SoundBackend, UsbBus};
+use common::util;
+use capabilities::Capabilities;
+
+/// Everything about the host the command line depends on
+pub struct HostFacts {
+    pub caps: Capabilities,
+    pub data: PathBuf,
+    pub efivars: PathBuf,
+    pub clientpipe: PathBuf,
+    pub monitor: PathBuf,
+    /// (hostbus, hostaddr) of the permanent usb devices that could be found, by index
+    pub usb_hosts: HashMap<usize, (String, String)>,
+    pub enable_gui: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdLine {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl CmdLine {
+    fn new(program: &str) -> CmdLine {
+        CmdLine { program: program.to_owned(), args: Vec::new() }
+    }
+
+    fn arg<S: AsRef<str>>(&mut self, arg: S) -> &mut CmdLine {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    fn args<I, S>(&mut self, args: I) -> &mut CmdLine
+        where I: IntoIterator<Item=S>, S: AsRef<str>
+    {
+        self.args.extend(args.into_iter().map(|x| x.as_ref().to_owned()));
+        self
+    }
+
+    /// Renders the command line so it can be pasted into a shell
+    pub fn to_shell(&self) -> String {
+        Some(&self.program).into_iter().chain(&self.args).map(|x| shell_quote(x)).join(" ")
+    }
+}
+
+fn shell_quote(arg: &str) -> Cow<str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./=,:+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+    }
+}
+
+/// ids of things we create ourselves, extra devices and objects must not use them
+const RESERVED_IDS: &[&str] = &["scsi", "iso", "cdrom", "unet", "sound0", "noaudio", "alsaaudio", "pulseaudio",
+                                "hugemem"];
+/// same as above, but followed by a number
+const RESERVED_ID_PREFIXES: &[&str] = &["bridge", "disk", "usb", "ohci", "uhci", "ehci", "xhci", "mem"];
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+/// QEMU ids have to start with a letter
+fn check_id(id: &str, ids: &mut HashSet<String>) -> Result<(), String> {
+    if !id.chars().next().map_or(false, |c| c.is_ascii_alphabetic()) || !valid_name(id) {
+        return Err(format!("invalid id `{}`", id));
+    }
+    let reserved = RESERVED_IDS.contains(&id) || RESERVED_ID_PREFIXES.iter().any(|prefix| {
+        id.starts_with(prefix) && id.len() > prefix.len() && id[prefix.len()..].chars().all(|c| c.is_digit(10))
+    });
+    if reserved {
+        return Err(format!("id `{}` is used by windows-gaming itself", id));
+    }
+    if !ids.insert(id.to_owned()) {
+        return Err(format!("id `{}` is used more than once", id));
+    }
+    Ok(())
+}
+
+fn check_properties(properties: &BTreeMap<String, String>) -> Result<(), String> {
+    for key in properties.keys() {
+        if !valid_name(key) || key == "id" {
+            return Err(format!("invalid property `{}`", key));
+        }
+    }
+    Ok(())
+}
+
+/// `,key=value` for every property, with commas in values escaped QEMU-style
+fn render_properties(properties: &BTreeMap<String, String>) -> String {
+    properties.iter().map(|(k, v)| format!(",{}={}", k, v.replace(',', ",,"))).join("")
+}
+
+/// Checks that the guest NUMA nodes cover every vCPU and all memory exactly once
+fn validate_numa(machine: &MachineConfig) -> Result<(), String> {
+    if machine.numa_nodes.is_empty() {
+        return Ok(());
+    }
+    if machine.hugepages().map_or(false, |x| x.numa_node.is_some()) {
+        return Err("hugepages.numa_node can't be combined with numa_nodes, set host_node on the nodes \
+                    instead".to_owned());
+    }
+
+    let mut memory = 0;
+    let mut vcpus = vec![0; machine.vcpus()];
+    for (i, node) in machine.numa_nodes.iter().enumerate() {
+        memory += node.memory_bytes().ok_or_else(|| format!("invalid memory size `{}`", node.memory))?;
+        for &vcpu in &node.vcpus {
+            match vcpus.get_mut(vcpu) {
+                Some(x) => *x += 1,
+                None => return Err(format!("node {} has vCPU {}, but -smp only has {}", i, vcpu, machine.vcpus())),
+            }
+        }
+    }
+    if Some(memory) != machine.memory_bytes() {
+        return Err(format!("the nodes have {} MiB of memory in total instead of {}", memory >> 20,
+                           machine.memory));
+    }
+    if let Some(vcpu) = vcpus.iter().position(|&x| x != 1) {
+        return Err(format!("vCPU {} has to be in exactly one node, not {}", vcpu, vcpus[vcpu]));
+    }
+    Ok(())
+}
+
+/// Parses guest PCI addresses like `05.0`, `0x5` or `1f.7` into slot and function
+fn parse_pci_addr(addr: &str) -> Option<(u8, u8)> {
+    let mut parts = addr.splitn(2, '.');
+    let slot = parts.next().unwrap();
+    let slot = if slot.starts_with("0x") { &slot[2..] } else { slot };
+    let slot = u8::from_str_radix(slot, 16).ok().filter(|&x| x < 0x20)?;
+    let function = match parts.next() {
+        Some(x) => x.parse().ok().filter(|&x| x < 8)?,
+        None => 0,
+    };
+    Some((slot, function))
+}
+
+/// Whether legacy VGA should be passed through for this device
+fn x_vga(device: &VfioDevice, enable_gui: bool) -> bool {
+    // the emulated VGA of the GUI mode would clash with it
+    device.options.x_vga.unwrap_or(device.options.primary_display && !enable_gui)
+}
+
+fn validate_vfio(devices: &[VfioDevice]) -> Result<(), String> {
+    let mut addrs = HashSet::new();
+    for device in devices {
+        let options = &device.options;
+        if options.romfile.as_ref().map_or(false, |x| x.is_empty()) {
+            return Err(format!("{}: romfile is empty", device.slot));
+        }
+        if let Some(ref bus) = options.bus {
+            if !valid_name(bus) {
+                return Err(format!("{}: invalid bus `{}`", device.slot, bus));
+            }
+        }
+        if let Some(ref addr) = options.addr {
+            let parsed = parse_pci_addr(addr)
+                .ok_or_else(|| format!("{}: invalid guest address `{}`", device.slot, addr))?;
+            if !addrs.insert((options.bus.clone(), parsed)) {
+                return Err(format!("{}: guest address {} is used more than once", device.slot, addr));
+            }
+        }
+    }
+    if devices.iter().filter(|x| x.options.primary_display).count() > 1 {
+        return Err("only one device can be the primary display".to_owned());
+    }
+    if devices.iter().filter(|x| x_vga(x, false)).count() > 1 {
+        return Err("only one device can have x_vga".to_owned());
+    }
+    Ok(())
+}
+
+fn vfio_device(device: &VfioDevice, enable_gui: bool) -> String {
+    let options = &device.options;
+    let mut arg = format!("vfio-pci,host={},multifunction=on", device.slot);
+    if let Some(ref bus) = options.bus {
+        arg.push_str(&format!(",bus={}", bus));
+    }
+    if let Some(ref addr) = options.addr {
+        arg.push_str(&format!(",addr={}", addr));
+    }
+    if x_vga(device, enable_gui) {
+        arg.push_str(",x-vga=on");
+    }
+    if let Some(rombar) = options.rombar {
+        arg.push_str(&format!(",rombar={}", rombar as u8));
+    }
+    if let Some(ref romfile) = options.romfile {
+        arg.push_str(&format!(",romfile={}", romfile.replace(',', ",,")));
+    }
+    if let Some(id) = options.sub_vendor_id {
+        arg.push_str(&format!(",x-pci-sub-vendor-id=0x{:04x}", id));
+    }
+    if let Some(id) = options.sub_device_id {
+        arg.push_str(&format!(",x-pci-sub-device-id=0x{:04x}", id));
+    }
+    arg
+}
+
+/// Makes sure the user supplied parts of the command line make sense.
+pub fn validate(cfg: &Config) -> Result<(), String> {
+    let machine = &cfg.machine;
+    if machine.memory_bytes().is_none() {
+        return Err(format!("invalid memory size `{}`", machine.memory));
+    }
+    if machine.sockets == Some(0) || machine.dies == Some(0) || machine.cores == 0 || machine.threads == Some(0) {
+        return Err("sockets, dies, cores and threads have to be at least 1".to_owned());
+    }
+    validate_numa(machine).map_err(|e| format!("numa_nodes: {}", e))?;
+    validate_vfio(&machine.pci_devices).map_err(|e| format!("pci_devices: {}", e))?;
+    if let Some(ref args) = cfg.additional_qemu_cmdline {
+        args.to_args().map_err(|e| format!("additional_qemu_cmdline: {}", e))?;
+    }
+
+    let mut ids = HashSet::new();
+    for object in &cfg.extra_objects {
+        let check = || -> Result<(), String> {
+            if !valid_name(&object.qom_type) {
+                return Err(format!("invalid type `{}`", object.qom_type));
+            }
+            check_id(&object.id, &mut ids)?;
+            check_properties(&object.properties)
+        };
+        check().map_err(|e| format!("extra_objects: {}", e))?;
+    }
+    for device in &cfg.extra_devices {
+        let check = || -> Result<(), String> {
+            if !valid_name(&device.driver) {
+                return Err(format!("invalid driver `{}`", device.driver));
+            }
+            if let Some(ref id) = device.id {
+                check_id(id, &mut ids)?;
+            }
+            check_properties(&device.properties)
+        };
+        check().map_err(|e| format!("extra_devices: {}", e))?;
+    }
+    Ok(())
+}
+
+pub fn build(cfg: &Config, host: &HostFacts) -> Result<CmdLine, String> {
+    validate(cfg)?;
+    let machine = &cfg.machine;
+    let caps = &host.caps;
+
+    let mut usernet = format!("user,id=unet,restrict=on,guestfwd=tcp:10.0.2.1:31337-unix:{}",
+                              host.clientpipe.display());
+
+    let machine_arg = Some(machine.machine_type()).into_iter()
+        .chain(machine.machine_options()).join(",");
+    let cpu_arg = Some(machine.cpu_model()).into_iter()
+        .chain(machine.cpu_flags()).chain(machine.hyperv()).join(",");
+    debug!("Machine: {}, CPU: {}", machine_arg, cpu_arg);
+
+    let mut qemu = CmdLine::new(machine.emulator());
+    qemu.args(&["-enable-kvm",
+                "-machine",
+                &machine_arg,
+                "-cpu",
+                &cpu_arg,
+                "-rtc",
+                "base=localtime",
+                "-nodefaults",
+                "-net",
+                "none",
+                "-display", "none", "-vga", "none",
+                "-qmp",
+                &format!("unix:{}", host.monitor.display()),
+                "-drive",
+                &format!("if=pflash,format=raw,readonly=on,file={}",
+                         host.data.join("ovmf-code.fd").display()),
+                "-drive",
+                &format!("if=pflash,format=raw,file={}", host.efivars.display()),
+                "-device", "virtio-scsi-pci,id=scsi",
+                "-drive", &format!("if=none,id=iso,media=cdrom,file={}",
+                                   host.data.join("windows-gaming-ga.iso").display()),
+                "-device", "scsi-cd,id=cdrom,drive=iso",
+    ]);
+
+    if let Some(ref samba) = cfg.samba {
+        usernet.push_str(&format!(",smb={}", samba.path));
+    }
+
+    if host.enable_gui {
+        qemu.args(&["-display", "gtk", "-vga", "qxl"]);
+    }
+
+    if let Some(ref setup) = cfg.setup {
+        if let Some(ref cdrom) = setup.cdrom {
+            qemu.arg("-cdrom").arg(cdrom);
+        }
+
+        if let Some(ref floppy) = setup.floppy {
+            qemu.arg("-drive").arg(format!("file={},index=0,if=floppy,readonly=on", floppy));
+        }
+    }
+
+    let hugepages = machine.hugepages();
+    // with guest NUMA nodes, memory comes from their backends instead
+    if let (Some(hugepages), true) = (hugepages.as_ref(), machine.numa_nodes.is_empty()) {
+        match hugepages.numa_node {
+            None => {
+                qemu.args(&["-mem-path", hugepages.mount(), "-mem-prealloc"]);
+            }
+            Some(node) => {
+                // a single guest node backed by the host node the pages were reserved on
+                let memory = machine.memory_bytes().ok_or("invalid memory size")?;
+                qemu.args(&["-object", &format!("memory-backend-file,id=hugemem,size={},mem-path={},\
+                                                 prealloc=on,host-nodes={},policy=bind",
+                                                memory, hugepages.mount(), node),
+                            "-numa", "node,memdev=hugemem"]);
+            }
+        }
+    }
+
+    qemu.args(&["-m", &machine.memory]);
+    let smp = machine.sockets.map(|x| format!("sockets={}", x)).into_iter()
+        .chain(machine.dies.map(|x| format!("dies={}", x)))
+        .chain(Some(format!("cores={},threads={}", machine.cores, machine.threads.unwrap_or(1))))
+        .join(",");
+    qemu.args(&["-smp", &smp]);
+
+    for (i, node) in machine.numa_nodes.iter().enumerate() {
+        let size = node.memory_bytes().ok_or("invalid memory size")?;
+        let mut backend = match hugepages {
+            Some(ref hugepages) => format!("memory-backend-file,id=mem{},size={},mem-path={},prealloc=on",
+                                           i, size, hugepages.mount()),
+            None => format!("memory-backend-ram,id=mem{},size={}", i, size),
+        };
+        if let Some(host_node) = node.host_node {
+            backend.push_str(&format!(",host-nodes={},policy=bind", host_node));
+        }
+        let cpus = util::cpu_ranges(&node.vcpus).iter().map(|x| format!(",cpus={}", x)).join("");
+        qemu.args(&["-object", &backend, "-numa", &format!("node,nodeid={}{},memdev=mem{}", i, cpus, i)]);
+    }
+
+    // objects go before all devices as those might refer to them
+    for object in &cfg.extra_objects {
+        qemu.args(&["-object", &format!("{},id={}{}", object.qom_type, object.id,
+                                        render_properties(&object.properties))]);
+    }
+
+    // the audiodev is added along with the other sound options below
+    let audiodev = cfg.sound.as_ref().map(|sound| match sound.backend {
+        SoundBackend::None => "noaudio",
+        SoundBackend::Alsa { .. } => "alsaaudio",
+        SoundBackend::PulseAudio { .. } => "pulseaudio",
+    });
+    if caps.hda_as_device() {
+        qemu.args(&["-device", "ich9-intel-hda,id=sound0"]);
+        match audiodev {
+            Some(id) => qemu.args(&["-device", &format!("hda-duplex,bus=sound0.0,audiodev={}", id)]),
+            None => qemu.args(&["-device", "hda-duplex,bus=sound0.0"]),
+        };
+    } else {
+        qemu.args(&["-soundhw", "hda"]);
+    }
+
+    for (idx, bridge) in machine.network.iter().flat_map(|x| x.bridges.iter()).enumerate() {
+        qemu.args(&["-netdev",
+                    &format!("bridge,id=bridge{},br={}", idx, bridge),
+                    "-device",
+                    &format!("e1000,netdev=bridge{}", idx)]);
+    }
+    qemu.args(&["-netdev", &usernet, "-device", "e1000,netdev=unet"]);
+
+    for device in cfg.machine.pci_devices.iter() {
+        qemu.args(&["-device", &vfio_device(device, host.enable_gui)]);
+    }
+
+    // create usb buses
+    {
+        let mut create_usb_buses = |name, typ, ports| {
+            let mut count = cfg.machine.usb_devices.iter().filter(|dev| dev.bus == typ).count();
+
+            if typ == UsbBus::Xhci {
+                // account for lighthouse usb-mouse and usb-kbd
+                count += 2;
+            }
+
+            let usable_ports = util::usable_ports(typ);
+            let num = (count + usable_ports - 1) / usable_ports;
+            for i in 0..num {
+                let device = format!("{},id={}{}{}", name, typ, i, ports);
+                qemu.args(&["-device", &device]);
+            }
+        };
+        create_usb_buses("pci-ohci", UsbBus::Ohci, ",num-ports=15");
+        create_usb_buses("ich9-usb-uhci1", UsbBus::Uhci, "");
+        create_usb_buses("ich9-usb-ehci1", UsbBus::Ehci, "");
+        create_usb_buses("qemu-xhci", UsbBus::Xhci, ",p2=15,p3=15");
+    }
+
+    let sorted = cfg.machine.usb_devices.iter().enumerate().sorted_by(|&(_, a), &(_, b)| a.bus.cmp(&b.bus));
+    let groups = sorted.iter().group_by(|&&(_, dev)| dev.bus);
+    for (bus, devices) in &groups {
+        let usable_ports = util::usable_ports(bus);
+        let mut i = 0;
+        for &(idx, dev) in devices {
+            let port = i;
+            i += 1;
+            if dev.permanent {
+                if let Some(&(ref hostbus, ref hostaddr)) = host.usb_hosts.get(&idx) {
+                    qemu.args(&["-device", &format!(
+                        "usb-host,hostbus={},hostaddr={},bus={}{}.0,port={}", hostbus, hostaddr,
+                        bus, port / usable_ports, (port % usable_ports) + 1)]);
+                }
+            }
+        }
+
+        if bus == UsbBus::Xhci {
+            // add lighthouse usb-mouse
+            let port = i;
+            qemu.args(&["-device", &format!("usb-mouse,bus=xhci{}.0,port={}",
+                                            port / usable_ports, (port % usable_ports) + 1)]);
+            // add lighthouse usb-kbd
+            let port = i + 1;
+            qemu.args(&["-device", &format!("usb-kbd,bus=xhci{}.0,port={}",
+                                            port / usable_ports, (port % usable_ports) + 1)]);
+        }
+    }
+
+    for (idx, drive) in machine.storage.iter().enumerate() {
+        qemu.args(&["-drive",
+                    &format!("file={},id=disk{},format={},if=none,cache={},aio=native",
+                             drive.path,
+                             idx,
+                             drive.format,
+                             drive.cache),
+                    "-device",
+                    &format!("scsi-hd,drive=disk{}", idx)]);
+    }
+
+    for device in &cfg.extra_devices {
+        let id = device.id.as_ref().map(|x| format!(",id={}", x)).unwrap_or_default();
+        qemu.args(&["-device", &format!("{}{}{}", device.driver, id, render_properties(&device.properties))]);
+    }
+
+    if let Some(sound) = &cfg.sound {
+        let mut audio_args = Vec::new();
+
+        audio_args.push(format!("timer-period={}",sound.timer_period.to_string()));
+
+        audio_args.push(format!("out.voices={}", sound.output.voices.to_string()));
+
+        match &sound.output.fixed {
+            &None => {
+                audio_args.push(format!("out.fixed-settings={}", "off"));
+            }
+            &Some(ref x) => {
+                audio_args.push(format!("out.fixed-settings={}", "on"));
+                audio_args.push(format!("out.frequency={}", x.frequency.to_string()));
+                audio_args.push(format!("out.format={}", &x.format.to_ascii_lowercase()));
+                audio_args.push(format!("out.channels={}", x.channels.to_string()));
+            }
+        }
+
+        audio_args.push(format!("in.voices={}", sound.input.voices.to_string()));
+
+        match &sound.input.fixed {
+            &None => {
+                audio_args.push(format!("in.fixed-settings={}", "off"));
+            }
+            &Some(ref x) => {
+                audio_args.push(format!("in.fixed-settings={}", "on"));
+                audio_args.push(format!("in.frequency={}", x.frequency.to_string()));
+                audio_args.push(format!("in.format={}", &x.format.to_ascii_lowercase()));
+                audio_args.push(format!("in.channels={}", x.channels.to_string()));
+            }
+        }
+
+        match &sound.backend {
+            &SoundBackend::None => {
+                qemu.args(&["-audiodev", "none,id=noaudio"]);
+            }
+            &SoundBackend::Alsa { ref sink, ref source } => {
+                audio_args.push(format!("out.dev={}", &sink.name));
+                //TODO: figure out if this still exists
+                /*qemu.env("QEMU_ALSA_DAC_SIZE_IN_USEC",
+                         if sink.unit == AlsaUnit::MicroSeconds { "1" } else { "0" });*/
+
+                audio_args.push(format!("out.buffer={}", sink.buffer_size.to_string()));
+                audio_args.push(format!("out.period-len={}", sink.period_size.to_string()));
+                audio_args.push(format!("out.try-poll={}",if sink.use_polling { "on" } else { "off" }));
+
+                audio_args.push(format!("in.dev={}", &source.name));
+                //TODO: figure out if this still exists
+                /*qemu.env("QEMU_ALSA_ADC_SIZE_IN_USEC",
+                         if source.unit == AlsaUnit::MicroSeconds { "1" } else { "0" });*/
+                audio_args.push(format!("in.buffer={}", source.buffer_size.to_string()));
+                audio_args.push(format!("in.period-len={}", source.period_size.to_string()));
+                audio_args.push(format!("in.try-poll={}", if source.use_polling { "on" } else { "off" }));
+
+                qemu.args(&["-audiodev", &format!("alsa,id=alsaaudio,{}",audio_args.join(","))]);
+            }
+            &SoundBackend::PulseAudio {
+                buffer_samples: _,
+                ref server,
+                ref sink_name,
+                ref source_name,
+            } => {
+                //TODO: figure out if this still exists
+                //qemu.env("QEMU_PA_SAMPLES", buffer_samples.to_string());
+                option2args(&mut audio_args, "server", server);
+                option2args(&mut audio_args, "out.name", sink_name);
+                option2args(&mut audio_args, "in.name", source_name);
+
+                qemu.args(&["-audiodev", &format!("pa,id=pulseaudio,{}",audio_args.join(","))]);
+            }
+        }
+    }
+
+    // anything the user wants to override has to come last
+    if let Some(ref args) = cfg.additional_qemu_cmdline {
+        qemu.args(args.to_args()?);
+    }
+
+    Ok(qemu)
+}
+
+fn option2args(args: &mut Vec<String>, name: &str, val: &Option<String>) {
+    match val {
+        &None => (),
+        &Some(ref x) => args.push(format!("{}={}", name, x)),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+    use common::config::{MachineConfig, VfioDevice, VfioOptions, PciId, UsbDevice, UsbBinding, UsbId, StorageDevice,
+                         NetworkConfig, SambaConfig, SoundConfig, QemuArgs, QemuObject, QemuDevice, Hugepages,
+                         HugepageConfig, PageSize, NumaNode};
+    use capabilities::QemuVersion;
+
+    fn host(version: QemuVersion) -> HostFacts {
+        let mut usb_hosts = HashMap::new();
+        usb_hosts.insert(1, ("3".to_owned(), "7".to_owned()));
+        HostFacts {
+            caps: Capabilities::new(version, &[], &[]),
+            data: Path::new("/usr/lib/windows-gaming").to_owned(),
+            efivars: Path::new("/var/lib/windows-gaming-driver/efivars.fd").to_owned(),
+            clientpipe: Path::new("/run/windows-gaming-driver/clientpipe.sock").to_owned(),
+            monitor: Path::new("/run/windows-gaming-driver/monitor.sock").to_owned(),
+            usb_hosts,
+            enable_gui: false,
+        }
+    }
+
+    fn minimal() -> Config {
+        Config {
+            machine: MachineConfig {
+                memory: "8G".to_owned(),
+                cores: 4,
+                threads: Some(2),
+                storage: vec![StorageDevice {
+                    path: "/dev/sda3".to_owned(),
+                    cache: "none".to_owned(),
+                    format: "raw".to_owned(),
+                }],
+                ..MachineConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    fn full() -> Config {
+        let mut cfg = minimal();
+        cfg.machine.hugepages = Some(Hugepages::Enabled(true));
+        cfg.machine.machine_type = Some("pc-q35-4.2".to_owned());
+        cfg.machine.pci_devices = vec![
+            VfioDevice {
+                resettable: false,
+                slot: "0000:01:00.0".to_owned(),
+                id: PciId { vendor: 0x10de, device: 0x1b80 },
+                options: VfioOptions {
+                    romfile: Some("/usr/share/vbios/gtx1080,patched.rom".to_owned()),
+                    bus: Some("pcie.0".to_owned()),
+                    addr: Some("05.0".to_owned()),
+                    sub_vendor_id: Some(0x1458),
+                    sub_device_id: Some(0x3702),
+                    primary_display: true,
+                    ..VfioOptions::default()
+                },
+            },
+            VfioDevice {
+                resettable: true,
+                slot: "0000:01:00.1".to_owned(),
+                id: PciId { vendor: 0x10de, device: 0x10f0 },
+                options: VfioOptions {
+                    bus: Some("pcie.0".to_owned()),
+                    addr: Some("05.1".to_owned()),
+                    rombar: Some(false),
+                    ..VfioOptions::default()
+                },
+            },
+        ];
+        cfg.machine.usb_devices = vec![
+            UsbDevice { binding: UsbBinding::ById(UsbId { vendor: 0x046d, product: 0xc52b }), permanent: false, bus: UsbBus::Xhci },
+            UsbDevice { binding: UsbBinding::ById(UsbId { vendor: 0x1532, product: 0x0043 }), permanent: true, bus: UsbBus::Ehci },
+        ];
+        cfg.machine.network = Some(NetworkConfig { bridges: vec!["br0".to_owned()] });
+        cfg.samba = Some(SambaConfig { path: "/home/foo/windows shared".to_owned() });
+        cfg.sound = Some(SoundConfig {
+            backend: SoundBackend::PulseAudio {
+                buffer_samples: 512,
+                server: None,
+                sink_name: Some("sink".to_owned()),
+                source_name: None,
+            },
+            ..SoundConfig::default()
+        });
+        let mut properties = BTreeMap::new();
+        properties.insert("filename".to_owned(), "/dev/urandom".to_owned());
+        cfg.extra_objects = vec![QemuObject { qom_type: "rng-random".to_owned(), id: "rng0".to_owned(), properties }];
+        let mut properties = BTreeMap::new();
+        properties.insert("rng".to_owned(), "rng0".to_owned());
+        cfg.extra_devices = vec![QemuDevice { driver: "virtio-rng-pci".to_owned(), id: None, properties }];
+        cfg.additional_qemu_cmdline = Some(QemuArgs::Shell(r#"-smbios "type=1,product=My PC" -name win"#.to_owned()));
+        cfg
+    }
+
+    /// Compares against a golden file with one argument per line
+    fn verify(cmdline: CmdLine, golden: &str) {
+        let expected: Vec<_> = golden.lines().collect();
+        let actual: Vec<_> = Some(&cmdline.program).into_iter().chain(&cmdline.args).map(|x| x.as_str()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn golden_minimal() {
+        verify(build(&minimal(), &host(QemuVersion::new(3, 1, 0))).unwrap(), include_str!("../testdata/cmdline/minimal.args"));
+    }
+
+    #[test]
+    fn golden_full() {
+        verify(build(&full(), &host(QemuVersion::new(4, 2, 0))).unwrap(), include_str!("../testdata/cmdline/full.args"));
+    }
+
+    #[test]
+    fn hugepages_on_node() {
+        let mut cfg = minimal();
+        cfg.machine.hugepages = Some(Hugepages::Config(HugepageConfig {
+            size: PageSize::Size2M,
+            mount: None,
+            numa_node: Some(1),
+        }));
+        let cmdline = build(&cfg, &host(QemuVersion::new(4, 2, 0))).unwrap();
+        let backend = cmdline.args.iter().position(|x| x == "-object").unwrap();
+        assert_eq!(&cmdline.args[backend..backend + 4],
+                   &["-object", "memory-backend-file,id=hugemem,size=8589934592,mem-path=/dev/hugepages,\
+                                 prealloc=on,host-nodes=1,policy=bind",
+                     "-numa", "node,memdev=hugemem"]);
+        assert!(!cmdline.args.contains(&"-mem-path".to_owned()));
+    }
+
+    #[test]
+    fn numa() {
+        let mut cfg = minimal();
+        cfg.machine.sockets = Some(1);
+        cfg.machine.dies = Some(2);
+        cfg.machine.cores = 2;
+        cfg.machine.numa_nodes = vec![
+            NumaNode { vcpus: vec![0, 1, 2, 3], memory: "4G".to_owned(), host_node: Some(0) },
+            NumaNode { vcpus: vec![4, 5, 6, 7], memory: "4096M".to_owned(), host_node: None },
+        ];
+        let cmdline = build(&cfg, &host(QemuVersion::new(4, 2, 0))).unwrap();
+        let smp = cmdline.args.iter().position(|x| x == "-smp").unwrap();
+        assert_eq!(&cmdline.args[smp..smp + 10],
+                   &["-smp", "sockets=1,dies=2,cores=2,threads=2",
+                     "-object", "memory-backend-ram,id=mem0,size=4294967296,host-nodes=0,policy=bind",
+                     "-numa", "node,nodeid=0,cpus=0-3,memdev=mem0",
+                     "-object", "memory-backend-ram,id=mem1,size=4294967296",
+                     "-numa", "node,nodeid=1,cpus=4-7,memdev=mem1"]);
+
+        cfg.machine.hugepages = Some(Hugepages::Enabled(true));
+        let cmdline = build(&cfg, &host(QemuVersion::new(4, 2, 0))).unwrap();
+        assert!(!cmdline.args.contains(&"-mem-path".to_owned()));
+        assert!(cmdline.args.contains(&"memory-backend-file,id=mem1,size=4294967296,\
+                                        mem-path=/dev/hugepages_vfio_1G,prealloc=on".to_owned()));
+    }
+
+    #[test]
+    fn invalid_numa() {
+        let mut cfg = minimal();
+        cfg.machine.numa_nodes = vec![
+            NumaNode { vcpus: vec![0, 1, 2, 3], memory: "4G".to_owned(), host_node: Some(0) },
+            NumaNode { vcpus: vec![4, 5, 6, 7], memory: "2G".to_owned(), host_node: Some(1) },
+        ];
+        // memory doesn't add up
+        assert!(validate(&cfg).is_err());
+        cfg.machine.numa_nodes[1].memory = "4G".to_owned();
+        assert!(validate(&cfg).is_ok());
+        // vCPU 7 is missing
+        cfg.machine.numa_nodes[1].vcpus = vec![3, 4, 5, 6];
+        assert!(validate(&cfg).is_err());
+        cfg.machine.numa_nodes[1].vcpus = vec![4, 5, 6, 7, 8];
+        assert!(validate(&cfg).is_err());
+    }
+
+    #[test]
+    fn invalid_vfio() {
+        let device = |addr: &str, primary_display| VfioDevice {
+            resettable: false,
+            slot: "0000:01:00.0".to_owned(),
+            id: PciId { vendor: 0x10de, device: 0x1b80 },
+            options: VfioOptions { addr: Some(addr.to_owned()), primary_display, ..VfioOptions::default() },
+        };
+        assert!(validate_vfio(&[device("05.0", true), device("0x5.1", false)]).is_ok());
+        assert!(validate_vfio(&[device("05.0", true), device("5.0", false)]).is_err());
+        assert!(validate_vfio(&[device("05.0", true), device("06.0", true)]).is_err());
+        assert!(validate_vfio(&[device("20.0", false)]).is_err());
+        assert!(validate_vfio(&[device("05.8", false)]).is_err());
+    }
+
+    #[test]
+    fn additional_list() {
+        let mut cfg = minimal();
+        cfg.additional_qemu_cmdline = Some(QemuArgs::List(vec!["-name".to_owned(), "my vm".to_owned()]));
+        let cmdline = build(&cfg, &host(QemuVersion::new(4, 2, 0))).unwrap();
+        assert_eq!(&cmdline.args[cmdline.args.len() - 2..], &["-name", "my vm"]);
+    }
+
+    #[test]
+    fn invalid_extras() {
+        let mut cfg = minimal();
+        cfg.additional_qemu_cmdline = Some(QemuArgs::Shell("-name 'unterminated".to_owned()));
+        assert!(validate(&cfg).is_err());
+
+        let mut cfg = minimal();
+        cfg.extra_devices = vec![QemuDevice { driver: "virtio-rng-pci".to_owned(), id: Some("disk0".to_owned()),
+                                              properties: BTreeMap::new() }];
+        assert!(validate(&cfg).is_err());
+
+        let mut cfg = minimal();
+        cfg.extra_objects = vec![QemuObject { qom_type: "rng-random".to_owned(), id: "rng0".to_owned(),
+                                              properties: BTreeMap::new() }];
+        cfg.extra_devices = vec![QemuDevice { driver: "virtio-rng-pci".to_owned(), id: Some("rng0".to_owned()),
+                                              properties: BTreeMap::new() }];
+        assert!(validate(&cfg).is_err());
+
+        let mut cfg = minimal();
+        cfg.extra_devices = vec![QemuDevice { driver: "virtio-rng-pci,x-foo=1".to_owned(), id: None,
+                                              properties: BTreeMap::new() }];
+        assert!(validate(&cfg).is_err());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("-device"), "-device");
+        assert_eq!(shell_quote("path=/home/foo/windows shared"), "'path=/home/foo/windows shared'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
@@ -0,0 +1,104 @@
+//! Blocking v2 client for the control socket, as used by the CLI.
+
+use std::os::unix::net::UnixStream;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json;
+
+use controller::Status;
+use super::codec::{ControlCmdIn, ControlCmdOut, ControlError, Hello, Request, Reply, PROTOCOL_VERSION};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServerMsg {
+    Hello(Hello),
+    Event(ControlCmdOut),
+    // has to be last as all of its fields are optional
+    Reply(Reply),
+}
+
+fn write_frame<T: Serialize>(stream: &mut UnixStream, msg: &T) -> io::Result<()> {
+    let json = serde_json::to_vec(msg)?;
+    let len = json.len() as u32;
+    stream.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    stream.write_all(&json)?;
+    stream.flush()
+}
+
+fn read_frame(stream: &mut UnixStream) -> io::Result<ServerMsg> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = len.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Connects to the control socket, performs the handshake and returns the stream.
+fn connect<P: AsRef<Path>>(socket_path: P) -> io::Result<Result<UnixStream, ControlError>> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    write_frame(&mut stream, &Hello { version: PROTOCOL_VERSION })?;
+    match read_frame(&mut stream)? {
+        ServerMsg::Hello(_) => Ok(Ok(stream)),
+        ServerMsg::Reply(Reply { error: Some(e), .. }) => Ok(Err(e)),
+        _ => Err(protocol_error("driver did not answer the handshake")),
+    }
+}
+
+/// Executes a command on the running driver and waits for its reply.
+///
+/// The outer result is about talking to the driver, the inner one about the command.
+/// Only the `Status` command returns a status.
+pub fn send<P: AsRef<Path>>(socket_path: P, cmd: ControlCmdIn) -> io::Result<Result<Option<Status>, ControlError>> {
+    let mut stream = match connect(socket_path)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    const ID: u64 = 1;
+    write_frame(&mut stream, &Request { id: ID, cmd })?;
+    loop {
+        match read_frame(&mut stream)? {
+            ServerMsg::Reply(Reply { id: Some(ID), error, status })
+                    | ServerMsg::Reply(Reply { id: None, error, status }) => {
+                return Ok(match error {
+                    Some(e) => Err(e),
+                    None => Ok(status),
+                });
+            }
+            // not for us
+            _ => (),
+        }
+    }
+}
+
+/// Subscribes to controller events, calling `f` for each of them until the driver goes away.
+pub fn subscribe<P, F>(socket_path: P, mut f: F) -> io::Result<Result<(), ControlError>>
+    where P: AsRef<Path>, F: FnMut(ControlCmdOut)
+{
+    let mut stream = match connect(socket_path)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    write_frame(&mut stream, &Request { id: 1, cmd: ControlCmdIn::Subscribe })?;
+    loop {
+        let msg = match read_frame(&mut stream) {
+            Ok(x) => x,
+            // the driver shut down
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(Ok(())),
+            Err(e) => return Err(e),
+        };
+        match msg {
+            ServerMsg::Event(event) => f(event),
+            ServerMsg::Reply(Reply { error: Some(e), .. }) => return Ok(Err(e)),
+            _ => (),
+        }
+    }
+}
@@ -1,9 +1,19 @@
 use std::io;
 use std::str;
-use bytes::{BytesMut, BufMut, LittleEndian, IntoBuf, Buf};
+use bytes::{BytesMut, BufMut, LittleEndian, BigEndian, IntoBuf, Buf};
 use tokio_io::codec::{Encoder, Decoder};
+use serde_json::{self, Value};
 
-#[derive(Debug, PartialEq, Eq)]
+use controller::{Status, State, IoStatus, ShutdownStep};
+
+/// The newest protocol version we speak
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Upper bound for v2 frames so a broken client can't make us buffer forever
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
 pub enum ControlCmdOut {
     MouseEdged {
         x: i32,
@@ -11,9 +21,48 @@ pub enum ControlCmdOut {
     },
     TemporaryLightAttached,
     TemporaryLightDetached,
+
+    // Everything below is only sent to subscribed v2 clients
+    GaStateChanged {
+        state: State,
+    },
+    IoStateChanged {
+        state: IoStatus,
+    },
+    /// The guest agent (re)started
+    GaHello,
+    /// The guest agent stopped answering pings or hung up
+    GaDied,
+    QemuSuspend,
+    QemuWakeup,
+    QemuReset,
+    QemuPowerdown,
+    ClipboardOwnerChanged {
+        owner: ClipboardOwner,
+    },
+    /// We escalated to the next step of shutting down
+    ShutdownStep {
+        step: ShutdownStep,
+    },
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl ControlCmdOut {
+    /// Machine readable representation for scripts
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Whose clipboard contents are the current ones
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipboardOwner {
+    Linux,
+    Windows,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
 pub enum ControlCmdIn {
     IoEntry,
     TryIoEntry,
@@ -26,15 +75,102 @@ pub enum ControlCmdIn {
         x: i32,
         y: i32,
     },
+    /// Only available in v2 as the legacy protocol has no replies
+    Status,
+    /// Start receiving controller events on this connection (v2 only)
+    Subscribe,
 }
 
-pub struct Codec;
+/// First frame of every v2 connection, in both directions
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+}
 
-impl Decoder for Codec {
-    type Item = ControlCmdIn;
-    type Error = io::Error;
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub id: u64,
+    #[serde(flatten)]
+    pub cmd: ControlCmdIn,
+}
+
+/// Answer to a `Request` with the same id. It succeeded unless there is an `error`.
+///
+/// Errors without an id concern the connection as a whole (failed handshake, garbage frames).
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Reply {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ControlError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+}
 
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<ControlCmdIn>> {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ControlError {
+    pub kind: ControlErrorKind,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlErrorKind {
+    /// The request could not be parsed
+    InvalidRequest,
+    /// The client wants a protocol version we don't speak
+    UnsupportedVersion,
+    /// The command can't be executed in the current state
+    InvalidState,
+    /// The command was executed but didn't succeed
+    Failed,
+}
+
+impl ControlError {
+    pub fn new<S: Into<String>>(kind: ControlErrorKind, message: S) -> ControlError {
+        ControlError { kind, message: message.into() }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ControlMsgIn {
+    Legacy(ControlCmdIn),
+    Hello(Hello),
+    Request(Request),
+    /// A v2 frame we couldn't make sense of, with its id (if we found one)
+    Invalid(Option<u64>, String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ControlMsgOut {
+    Event(ControlCmdOut),
+    Hello(Hello),
+    Reply(Reply),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    /// Single opcode bytes, as spoken by windows-edge-grab
+    Legacy,
+    /// Length-prefixed JSON frames
+    V2,
+}
+
+/// Speaks both the legacy and the v2 protocol, deciding by the first byte a client sends.
+///
+/// The big-endian length prefix of any v2 frame starts with a zero byte, which never was a
+/// valid legacy opcode.
+#[derive(Default)]
+pub struct Codec {
+    protocol: Option<Protocol>,
+}
+
+impl Codec {
+    pub fn new() -> Codec {
+        Codec::default()
+    }
+
+    fn decode_legacy(&mut self, buf: &mut BytesMut) -> io::Result<Option<ControlCmdIn>> {
         let mut size = 1;
         let ret = match buf.get(0).cloned() {
             Some(1) => ControlCmdIn::IoEntry,
@@ -55,32 +191,104 @@ impl Decoder for Codec {
             }
             Some(x) => {
                 warn!("control sent invalid request {}", x);
-                // no idea how to proceed as the request might have payload
-                // this essentially just hangs the connection forever
-                return Ok(None);
+                // no idea how to proceed as the request might have payload,
+                // so all we can do is hang up
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid legacy control request"));
             }
             None => return Ok(None),
         };
         buf.split_to(size);
         Ok(Some(ret))
     }
+
+    fn decode_v2(&mut self, buf: &mut BytesMut) -> io::Result<Option<ControlMsgIn>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = (&buf[..4]).into_buf().get_u32::<BigEndian>() as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "control frame too large"));
+        }
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+
+        buf.split_to(4);
+        let frame = buf.split_to(len);
+        let value: Value = match serde_json::from_slice(&frame) {
+            Ok(x) => x,
+            Err(e) => return Ok(Some(ControlMsgIn::Invalid(None, e.to_string()))),
+        };
+
+        // requests carry an id, the handshake doesn't
+        let id = value.get("id").and_then(Value::as_u64);
+        Ok(Some(match id {
+            Some(id) => match serde_json::from_value(value) {
+                Ok(req) => ControlMsgIn::Request(req),
+                Err(e) => ControlMsgIn::Invalid(Some(id), e.to_string()),
+            },
+            None => match serde_json::from_value(value) {
+                Ok(hello) => ControlMsgIn::Hello(hello),
+                Err(e) => ControlMsgIn::Invalid(None, e.to_string()),
+            },
+        }))
+    }
+}
+
+impl Decoder for Codec {
+    type Item = ControlMsgIn;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<ControlMsgIn>> {
+        let protocol = match self.protocol {
+            Some(x) => x,
+            None => {
+                let protocol = match buf.get(0) {
+                    Some(&0) => Protocol::V2,
+                    Some(_) => Protocol::Legacy,
+                    None => return Ok(None),
+                };
+                self.protocol = Some(protocol);
+                protocol
+            }
+        };
+
+        match protocol {
+            Protocol::Legacy => Ok(self.decode_legacy(buf)?.map(ControlMsgIn::Legacy)),
+            Protocol::V2 => self.decode_v2(buf),
+        }
+    }
 }
 
 impl Encoder for Codec {
-    type Item = ControlCmdOut;
+    type Item = ControlMsgOut;
     type Error = io::Error;
 
-    fn encode(&mut self, cmd: ControlCmdOut, buf: &mut BytesMut) -> io::Result<()> {
+    fn encode(&mut self, msg: ControlMsgOut, buf: &mut BytesMut) -> io::Result<()> {
+        if self.protocol == Some(Protocol::V2) {
+            let json = match msg {
+                ControlMsgOut::Event(ref x) => serde_json::to_vec(x),
+                ControlMsgOut::Hello(ref x) => serde_json::to_vec(x),
+                ControlMsgOut::Reply(ref x) => serde_json::to_vec(x),
+            }.unwrap();
+            buf.reserve(4 + json.len());
+            buf.put_u32::<BigEndian>(json.len() as u32);
+            buf.put_slice(&json);
+            return Ok(());
+        }
+
         buf.reserve(1);
-        match cmd {
-            ControlCmdOut::MouseEdged { x, y } => {
+        match msg {
+            ControlMsgOut::Event(ControlCmdOut::MouseEdged { x, y }) => {
                 buf.put_u8(1);
                 buf.reserve(8);
                 buf.put_i32::<LittleEndian>(x);
                 buf.put_i32::<LittleEndian>(y);
             }
-            ControlCmdOut::TemporaryLightAttached => buf.put_u8(2),
-            ControlCmdOut::TemporaryLightDetached => buf.put_u8(3),
+            ControlMsgOut::Event(ControlCmdOut::TemporaryLightAttached) => buf.put_u8(2),
+            ControlMsgOut::Event(ControlCmdOut::TemporaryLightDetached) => buf.put_u8(3),
+            // the legacy protocol has no concept of these
+            ControlMsgOut::Event(_) | ControlMsgOut::Hello(_) | ControlMsgOut::Reply(_) => (),
         }
         Ok(())
     }
@@ -90,30 +298,150 @@ impl Encoder for Codec {
 mod test {
     use super::*;
     use bytes::BytesMut;
-    use tokio_io::codec::Decoder;
+    use tokio_io::codec::{Decoder, Encoder};
 
     fn verify(data: &[u8], expected: Option<ControlCmdIn>, remaining: usize) {
         let mut bytes = BytesMut::new();
         bytes.extend(data);
-        assert_eq!(Codec.decode(&mut bytes).unwrap(), expected);
+        assert_eq!(Codec::new().decode(&mut bytes).unwrap(), expected.map(ControlMsgIn::Legacy));
         assert_eq!(bytes.len(), remaining);
     }
 
+    fn frame(json: &str) -> Vec<u8> {
+        let mut data = vec![0, 0, 0, json.len() as u8];
+        data.extend(json.bytes());
+        data
+    }
+
     #[test] fn none() { verify(&[], None, 0); }
-    #[test] fn invalid() { verify(&[0], None, 1); }
+    #[test] fn v2_partial_prefix() { verify(&[0], None, 1); }
     #[test] fn io_entry() { verify(&[1], Some(ControlCmdIn::IoEntry), 0); }
     #[test] fn shutdown() { verify(&[2], Some(ControlCmdIn::Shutdown), 0); }
     #[test] fn force_io_entry() { verify(&[3], Some(ControlCmdIn::ForceIoEntry), 0); }
     #[test] fn io_exit() { verify(&[4], Some(ControlCmdIn::IoExit), 0); }
     #[test] fn suspend() { verify(&[5], Some(ControlCmdIn::Suspend), 0); }
 
+    #[test]
+    fn invalid() {
+        // there's no telling where the next request would start, so we hang up
+        let err = Codec::new().decode(&mut BytesMut::from(&[9][..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn multiple() {
+        let mut codec = Codec::new();
         let mut bytes = BytesMut::new();
         bytes.extend(&[1,2]);
-        assert_eq!(Codec.decode(&mut bytes).unwrap(), Some(ControlCmdIn::IoEntry));
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(ControlMsgIn::Legacy(ControlCmdIn::IoEntry)));
         assert_eq!(bytes.len(), 1);
-        assert_eq!(Codec.decode(&mut bytes).unwrap(), Some(ControlCmdIn::Shutdown));
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(ControlMsgIn::Legacy(ControlCmdIn::Shutdown)));
         assert_eq!(bytes.len(), 0);
     }
+
+    #[test]
+    fn legacy_unknown() {
+        let mut bytes = BytesMut::new();
+        bytes.extend(&[42]);
+        assert!(Codec::new().decode(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn v2_handshake_and_request() {
+        let mut codec = Codec::new();
+        let mut bytes = BytesMut::new();
+        bytes.extend(frame(r#"{"version": 2}"#));
+        bytes.extend(frame(r#"{"id": 5, "command": "temporary_light_entry", "x": 1, "y": -2}"#));
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(ControlMsgIn::Hello(Hello { version: 2 })));
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(ControlMsgIn::Request(Request {
+            id: 5,
+            cmd: ControlCmdIn::TemporaryLightEntry { x: 1, y: -2 },
+        })));
+        assert_eq!(bytes.len(), 0);
+    }
+
+    #[test]
+    fn v2_partial() {
+        let data = frame(r#"{"version": 2}"#);
+        let mut bytes = BytesMut::new();
+        bytes.extend(&data[..6]);
+        assert_eq!(Codec::new().decode(&mut bytes).unwrap(), None);
+        assert_eq!(bytes.len(), 6);
+    }
+
+    #[test]
+    fn v2_unknown_command() {
+        let mut codec = Codec::new();
+        let mut bytes = BytesMut::new();
+        bytes.extend(frame(r#"{"id": 3, "command": "fly"}"#));
+        bytes.extend(frame(r#"{"id": 4, "command": "io_exit"}"#));
+        match codec.decode(&mut bytes).unwrap() {
+            Some(ControlMsgIn::Invalid(Some(3), _)) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+        // the connection keeps working
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(ControlMsgIn::Request(Request {
+            id: 4,
+            cmd: ControlCmdIn::IoExit,
+        })));
+    }
+
+    #[test]
+    fn v2_status() {
+        let mut bytes = BytesMut::new();
+        bytes.extend(frame(r#"{"version": 2}"#));
+        bytes.extend(frame(r#"{"id": 1, "command": "status"}"#));
+        let mut codec = Codec::new();
+        codec.decode(&mut bytes).unwrap();
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(ControlMsgIn::Request(Request {
+            id: 1,
+            cmd: ControlCmdIn::Status,
+        })));
+    }
+
+    #[test]
+    fn v2_event() {
+        let mut bytes = BytesMut::new();
+        bytes.extend(frame(r#"{"version": 2}"#));
+        let mut codec = Codec::new();
+        codec.decode(&mut bytes).unwrap();
+
+        let event = ControlCmdOut::IoStateChanged { state: IoStatus::FullEntry };
+        codec.encode(ControlMsgOut::Event(event), &mut bytes).unwrap();
+        assert_eq!(&bytes[..], &frame(r#"{"event":"io_state_changed","state":"full_entry"}"#)[..]);
+    }
+
+    #[test]
+    fn v2_shutdown_step() {
+        let mut bytes = BytesMut::new();
+        bytes.extend(frame(r#"{"version": 2}"#));
+        let mut codec = Codec::new();
+        codec.decode(&mut bytes).unwrap();
+
+        let event = ControlCmdOut::ShutdownStep { step: ShutdownStep::Acpi };
+        codec.encode(ControlMsgOut::Event(event), &mut bytes).unwrap();
+        assert_eq!(&bytes[..], &frame(r#"{"event":"shutdown_step","step":"acpi"}"#)[..]);
+    }
+
+    #[test]
+    fn legacy_drops_new_events() {
+        let mut bytes = BytesMut::new();
+        bytes.extend(&[7]);
+        let mut codec = Codec::new();
+        codec.decode(&mut bytes).unwrap();
+
+        codec.encode(ControlMsgOut::Event(ControlCmdOut::GaHello), &mut bytes).unwrap();
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn v2_reply() {
+        let mut codec = Codec::new();
+        let mut bytes = BytesMut::new();
+        bytes.extend(frame(r#"{"version": 2}"#));
+        codec.decode(&mut bytes).unwrap();
+
+        codec.encode(ControlMsgOut::Reply(Reply { id: Some(1), error: None, status: None }), &mut bytes).unwrap();
+        assert_eq!(&bytes[..], &frame(r#"{"id":1}"#)[..]);
+    }
 }
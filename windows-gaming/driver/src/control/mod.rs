@@ -1,6 +1,8 @@
 mod codec;
+mod client;
 
-pub use self::codec::{ControlCmdOut, ControlCmdIn};
+pub use self::codec::{ControlCmdOut, ControlCmdIn, ControlError, ControlErrorKind, ClipboardOwner, PROTOCOL_VERSION};
+pub use self::client::{send as send_command, subscribe};
 
 use std::os::unix::net::{UnixListener as StdUnixListener};
 use std::io::Error;
@@ -8,74 +10,159 @@ use std::rc::Rc;
 use std::cell::RefCell;
 
 use futures::{future, Stream, Future, Sink};
-use futures::unsync::mpsc;
+use futures::unsync::mpsc::{self, UnboundedSender};
 use tokio_core::reactor::Handle;
 use tokio_io::AsyncRead;
 use tokio_uds::UnixListener as TokioUnixListener;
 
-use controller::Controller;
-use self::codec::Codec;
+use controller::{Controller, Status};
+use self::codec::{Codec, ControlMsgIn, ControlMsgOut, Hello, Request, Reply};
 
 type Handler<'a> = Box<Future<Item=(), Error=Error> + 'a>;
+type Outcome = Box<Future<Item=Option<Status>, Error=ControlError>>;
+
+/// Replies to a request the controller may refuse in its current state
+fn outcome(res: Result<(), &'static str>) -> Outcome {
+    Box::new(future::result(res.map(|()| None).map_err(|e| ControlError::new(ControlErrorKind::InvalidState, e))))
+}
+
+/// State of a single control client
+struct Connection {
+    controller: Rc<RefCell<Controller>>,
+    sender: UnboundedSender<ControlMsgOut>,
+    handle: Handle,
+    temp_entry: bool,
+    subscribed: bool,
+    // None until a v2 client completed the handshake
+    version: Option<u32>,
+}
+
+impl Connection {
+    fn reply(&self, id: Option<u64>, error: Option<ControlError>) {
+        let _ = (&self.sender).send(ControlMsgOut::Reply(Reply { id, error, status: None }));
+    }
+
+    fn handle_msg(&mut self, msg: ControlMsgIn) -> Box<Future<Item=(), Error=()>> {
+        match msg {
+            ControlMsgIn::Legacy(cmd) => {
+                // legacy clients have no way of learning about errors, so we just hang up on them
+                return Box::new(self.execute(cmd).map(|_| ())
+                                .map_err(|e| warn!("Control request failed: {}", e.message)));
+            }
+            ControlMsgIn::Hello(Hello { version }) => {
+                if version != PROTOCOL_VERSION {
+                    warn!("Control client wants unsupported protocol version {}", version);
+                    self.reply(None, Some(ControlError::new(ControlErrorKind::UnsupportedVersion,
+                        format!("unsupported protocol version {}, we speak {}", version, PROTOCOL_VERSION))));
+                    return Box::new(future::err(()));
+                }
+                self.version = Some(version);
+                let _ = (&self.sender).send(ControlMsgOut::Hello(Hello { version: PROTOCOL_VERSION }));
+            }
+            ControlMsgIn::Request(_) if self.version.is_none() => {
+                self.reply(None, Some(ControlError::new(ControlErrorKind::InvalidState,
+                                                        "handshake required")));
+                return Box::new(future::err(()));
+            }
+            ControlMsgIn::Request(Request { id, cmd }) => {
+                // a temporary entry ends with anything but an IoExit, and so does the connection
+                let close = self.temp_entry && cmd != ControlCmdIn::IoExit;
+                let sender = self.sender.clone();
+                return Box::new(self.execute(cmd).then(move |res| {
+                    let reply = match res {
+                        Ok(status) => Reply { id: Some(id), error: None, status },
+                        Err(e) => Reply { id: Some(id), error: Some(e), status: None },
+                    };
+                    let _ = (&sender).send(ControlMsgOut::Reply(reply));
+                    if close { Err(()) } else { Ok(()) }
+                }));
+            }
+            ControlMsgIn::Invalid(id, e) => {
+                warn!("Control client sent an invalid request: {}", e);
+                self.reply(id, Some(ControlError::new(ControlErrorKind::InvalidRequest, e)));
+            }
+        }
+        Box::new(future::ok(()))
+    }
+
+    fn execute(&mut self, cmd: ControlCmdIn) -> Outcome {
+        let mut controller = self.controller.borrow_mut();
+        info!("Control request: {:?}", cmd);
+        if self.temp_entry {
+            controller.temporary_exit();
+            if cmd == ControlCmdIn::IoExit {
+                self.temp_entry = false;
+                return Box::new(future::ok(None));
+            }
+            return Box::new(future::err(ControlError::new(ControlErrorKind::InvalidState,
+                "only io_exit is allowed during a temporary light entry")));
+        }
+        match cmd {
+            ControlCmdIn::IoEntry => return outcome(controller.io_attach()),
+            ControlCmdIn::Shutdown => controller.shutdown(),
+            ControlCmdIn::ForceIoEntry => return outcome(controller.io_force_attach()),
+            ControlCmdIn::IoExit => return outcome(controller.io_detach()),
+            ControlCmdIn::Suspend => {
+                return Box::new(controller.suspend().map(|()| None).map_err(|()| {
+                    ControlError::new(ControlErrorKind::Failed, "Windows did not finish suspending")
+                }));
+            }
+            ControlCmdIn::TryIoEntry => return outcome(controller.try_attach()),
+            ControlCmdIn::LightEntry => return outcome(controller.light_attach()),
+            ControlCmdIn::Status => return Box::new(future::ok(Some(controller.status()))),
+            ControlCmdIn::Subscribe if self.subscribed => (),
+            ControlCmdIn::Subscribe => {
+                let (send, receiver) = mpsc::unbounded();
+                controller.subscribe(send);
+                self.subscribed = true;
+                // ends once the connection is gone, the controller notices on its next event
+                let sender = self.sender.clone();
+                self.handle.spawn(receiver.for_each(move |event| {
+                    (&sender).send(ControlMsgOut::Event(event)).map_err(|_| ())
+                }));
+            }
+            ControlCmdIn::TemporaryLightEntry { x, y } => {
+                let (send, receiver) = mpsc::unbounded();
+                let res = controller.temporary_entry(send, x, y);
+                if !res {
+                    warn!("Temporary entry failed");
+                    return Box::new(future::err(ControlError::new(ControlErrorKind::InvalidState,
+                        "temporary entry requires a running guest agent and detached io")));
+                }
+                (&self.sender).send(ControlMsgOut::Event(ControlCmdOut::TemporaryLightAttached)).unwrap();
+                self.temp_entry = true;
+                let receiver = receiver.map_err(|_| ());
+                let controller = self.controller.clone();
+                let sender = self.sender.clone();
+                let sender2 = self.sender.clone();
+                self.handle.spawn(receiver.for_each(move |data| (&sender).send(ControlMsgOut::Event(data)).map_err(|_| ()))
+                    .then(move |_| {
+                        controller.borrow_mut().temporary_exit();
+                        let _ = (&sender2).send(ControlMsgOut::Event(ControlCmdOut::TemporaryLightDetached));
+                        Ok(())
+                    }));
+            }
+        }
+        Box::new(future::ok(None))
+    }
+}
 
 pub fn create<'a>(socket: StdUnixListener, handle: &'a Handle, controller: Rc<RefCell<Controller>>) -> Handler<'a> {
     let socket = TokioUnixListener::from_listener(socket, &handle).unwrap();
     let handle_inner = handle.clone();
     let handler = socket.incoming().for_each(move |(socket, _)| {
-        let (writer, reader) = socket.framed(Codec).split();
+        let (writer, reader) = socket.framed(Codec::new()).split();
         let (sender, recv) = mpsc::unbounded();
-        let sender = Rc::new(RefCell::new(sender));
         let writer = writer.sink_map_err(|_| ()).send_all(recv).map_err(|_| ()).map(|_| ());
-        let controller_rc = controller.clone();
-        let mut temp_entry = false;
-        let handle_inner = handle_inner.clone();
-        let reader = reader.map_err(|_| ()).for_each(move |req| {
-            let mut controller = controller_rc.borrow_mut();
-            info!("Control request: {:?}", req);
-            if temp_entry {
-                match req {
-                    ControlCmdIn::IoExit => {
-                        controller.temporary_exit();
-                        temp_entry = false;
-                    }
-                    _ => {
-                        controller.temporary_exit();
-                        return Box::new(future::err(())) as Box<Future<Item=_, Error=_>>;
-                    }
-                }
-                return Box::new(future::ok(()));
-            }
-            match req {
-                ControlCmdIn::IoEntry => controller.io_attach(),
-                ControlCmdIn::Shutdown => controller.shutdown(),
-                ControlCmdIn::ForceIoEntry => controller.io_force_attach(),
-                ControlCmdIn::IoExit => controller.io_detach(),
-                ControlCmdIn::Suspend => return controller.suspend(),
-                ControlCmdIn::TryIoEntry => controller.try_attach(),
-                ControlCmdIn::LightEntry => controller.light_attach(),
-                ControlCmdIn::TemporaryLightEntry { x, y } => {
-                    let (send, receiver) = mpsc::unbounded();
-                    let res = controller.temporary_entry(send, x, y);
-                    if !res {
-                        warn!("Temporary entry failed, closing connection");
-                        return Box::new(future::err(()));
-                    }
-                    (&*sender.borrow()).send(ControlCmdOut::TemporaryLightAttached).unwrap();
-                    temp_entry = true;
-                    let receiver = receiver.map_err(|_| ());
-                    let controller = controller_rc.clone();
-                    let sender = sender.clone();
-                    let sender2 = sender.clone();
-                    handle_inner.spawn(receiver.for_each(move |data| (&*sender.borrow()).send(data).map_err(|_| ()))
-                        .then(move |_| {
-                            controller.borrow_mut().temporary_exit();
-                            let _ = (&*sender2.borrow()).send(ControlCmdOut::TemporaryLightDetached);
-                            Ok(())
-                        }));
-                }
-            }
-            Box::new(future::ok(()))
-        }).then(|_| Ok(()));
+        let mut connection = Connection {
+            controller: controller.clone(),
+            sender,
+            handle: handle_inner.clone(),
+            temp_entry: false,
+            subscribed: false,
+            version: None,
+        };
+        let reader = reader.map_err(|_| ()).for_each(move |msg| connection.handle_msg(msg)).then(|_| Ok(()));
 
         handle.spawn(writer.select(reader).then(|_| Ok(())));
         Ok(())
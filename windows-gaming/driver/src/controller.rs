@@ -1,9 +1,11 @@
-use std::mem;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::ffi::OsStr;
 use std::cell::RefCell;
 use std::process::Command;
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use std::fmt::{Display, Formatter, Result as FmtResult};
 
 use itertools::Itertools;
 use libudev::{Result as UdevResult, Context, Enumerator};
@@ -11,21 +13,32 @@ use futures::unsync::mpsc::UnboundedSender;
 use futures::unsync::oneshot::{self, Sender};
 use futures::Future;
 use futures::future;
+use tokio_core::reactor::{Handle, Timeout};
 
 use common::config::{UsbId, UsbPort, UsbBinding, MachineConfig, HotKeyAction, Action};
 use common::util;
 use clientpipe::{GaCmdOut, ClipboardMessage, ClipboardType, ClipboardTypes, RegisterHotKey, Point};
-use control::ControlCmdOut;
-use monitor::QmpCommand;
+use control::{ControlCmdOut, ClipboardOwner};
+use monitor::{QmpCommand, QmpClient, QmpFuture, QmpError, Ret, CommandInfo, CpuInfoFast};
+use capabilities::REQUIRED_QMP_COMMANDS;
 use sd_notify;
+use pinning;
+use serde_json;
+use libc;
 use libinput::Input;
 use clipboard::{ClipboardRequestEvent, ClipboardRequestResponse};
 use release_all_keys::EVENTS as RELEASE_ALL_KEYS;
 
+/// How long QEMU may take to report a detached USB device as deleted
+const DEVICE_DELETE_TIMEOUT: u64 = 10;
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+/// How long QEMU may take to exit after `quit` before we kill it
+const QUIT_TIMEOUT: u64 = 10;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 /// States the state machine of this Controller can have
-enum State {
+pub enum State {
     /// GA is down
     Down,
     /// GA is up
@@ -49,6 +62,183 @@ enum IoState {
     FullEntry,
 }
 
+/// `IoState` as reported to the outside world
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoStatus {
+    Detached,
+    LightEntry,
+    TemporaryLightEntry,
+    AwaitingUpgrade,
+    FullEntry,
+}
+
+impl<'a> From<&'a IoState> for IoStatus {
+    fn from(state: &IoState) -> IoStatus {
+        match *state {
+            IoState::Detached => IoStatus::Detached,
+            IoState::LightEntry => IoStatus::LightEntry,
+            IoState::TemporaryLightEntry(_) => IoStatus::TemporaryLightEntry,
+            IoState::AwaitingUpgrade => IoStatus::AwaitingUpgrade,
+            IoState::FullEntry => IoStatus::FullEntry,
+        }
+    }
+}
+
+/// Steps of shutting down, each one less friendly than the one before
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownStep {
+    /// The guest agent asks Windows to shut down
+    GuestAgent,
+    /// ACPI power button
+    Acpi,
+    /// QMP `quit`, Windows doesn't get a say anymore
+    Quit,
+    /// SIGKILL to the QEMU process group
+    Kill,
+}
+
+impl ShutdownStep {
+    /// Where to start, nobody inside a sleeping Windows is going to react to the friendly steps
+    fn first(ga: State) -> ShutdownStep {
+        match ga {
+            State::Up | State::Pinging => ShutdownStep::GuestAgent,
+            State::Down | State::Resuming => ShutdownStep::Acpi,
+            State::Suspending | State::Suspended => ShutdownStep::Quit,
+        }
+    }
+
+    fn next(self) -> Option<ShutdownStep> {
+        match self {
+            ShutdownStep::GuestAgent => Some(ShutdownStep::Acpi),
+            ShutdownStep::Acpi => Some(ShutdownStep::Quit),
+            ShutdownStep::Quit => Some(ShutdownStep::Kill),
+            ShutdownStep::Kill => None,
+        }
+    }
+
+    fn status(self) -> &'static str {
+        match self {
+            ShutdownStep::GuestAgent => "Shutting down through the guest agent ...",
+            ShutdownStep::Acpi => "Shutting down through ACPI ...",
+            ShutdownStep::Quit => "Windows didn't shut down, telling QEMU to quit ...",
+            ShutdownStep::Kill => "QEMU didn't quit, killing it ...",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AttachedUsbDevice {
+    pub id: String,
+    pub binding: UsbBinding,
+}
+
+/// Snapshot of what the driver is currently doing
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub ga: State,
+    pub io: IoStatus,
+    pub usb_attached: Vec<AttachedUsbDevice>,
+    pub qemu_pid: u32,
+    pub uptime_secs: u64,
+    pub ga_ping_rtt_ms: Option<u64>,
+}
+
+impl Status {
+    /// Machine readable representation for scripts
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        writeln!(f, "Guest agent: {:?}", self.ga)?;
+        writeln!(f, "IO: {:?}", self.io)?;
+        match self.ga_ping_rtt_ms {
+            Some(rtt) => writeln!(f, "Last ping: {} ms", rtt)?,
+            None => writeln!(f, "Last ping: never answered")?,
+        }
+        writeln!(f, "QEMU PID: {}", self.qemu_pid)?;
+        writeln!(f, "Uptime: {}:{:02}:{:02}", self.uptime_secs / 3600, self.uptime_secs / 60 % 60,
+                 self.uptime_secs % 60)?;
+        if self.usb_attached.is_empty() {
+            write!(f, "No USB devices attached")
+        } else {
+            write!(f, "USB devices attached:")?;
+            for dev in &self.usb_attached {
+                write!(f, "\n\t{}: {:?}", dev.id, dev.binding)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Which USB devices Windows has, and which it is still giving back.
+///
+/// QEMU rejects a device_add for an id that is still in use, so attaching a device
+/// that is being removed has to wait until it's gone.
+#[derive(Default)]
+struct UsbDevices {
+    // qdev ids of the usb devices currently attached to Windows
+    attached: HashSet<String>,
+    // usb devices we sent device_del for that didn't report DEVICE_DELETED yet
+    detaching: HashSet<String>,
+    // device_adds waiting for a pending removal of the same id to complete
+    deferred: HashMap<String, QmpCommand>,
+}
+
+impl UsbDevices {
+    /// Returns the device_add if it can be sent right away, otherwise it's deferred
+    fn attach(&mut self, id: String, cmd: QmpCommand) -> Option<QmpCommand> {
+        if self.detaching.contains(&id) {
+            debug!("{} is still being removed, deferring device_add", id);
+            self.deferred.insert(id, cmd);
+            None
+        } else {
+            self.attached.insert(id);
+            Some(cmd)
+        }
+    }
+
+    fn attach_failed(&mut self, id: &str) {
+        self.attached.remove(id);
+    }
+
+    /// Starts removing everything, returning the ids to send device_del for
+    fn detach_all(&mut self) -> Vec<String> {
+        // these never made it to Windows in the first place
+        self.deferred.clear();
+        let mut ids: Vec<_> = self.attached.drain().collect();
+        ids.sort();
+        self.detaching.extend(ids.iter().cloned());
+        ids
+    }
+
+    /// A device is gone for good, returning the device_add that waited for that
+    fn gone(&mut self, id: &str) -> Option<QmpCommand> {
+        if !self.detaching.remove(id) {
+            return None;
+        }
+        let cmd = self.deferred.remove(id);
+        if cmd.is_some() {
+            debug!("Performing deferred device_add for {}", id);
+            self.attached.insert(id.to_owned());
+        }
+        cmd
+    }
+
+    /// Stops waiting for the removals, returning the device_adds that waited for them.
+    ///
+    /// Should QEMU still have one of those devices, it rejects the device_add and we get to know.
+    fn give_up(&mut self) -> Vec<(String, QmpCommand)> {
+        let mut ids: Vec<_> = self.detaching.iter().cloned().collect();
+        ids.sort();
+        ids.into_iter().filter_map(|id| self.gone(&id).map(|cmd| (id, cmd))).collect()
+    }
+}
+
 pub struct Controller {
     machine_config: MachineConfig,
 
@@ -57,6 +247,17 @@ pub struct Controller {
     // senders to be sent to when windows finished suspending
     suspend_senders: Vec<Sender<()>>,
 
+    usb: UsbDevices,
+    // bumped on every detach so stale timeouts can be told apart
+    detach_generation: u64,
+    // how far we got shutting down, if we are
+    shutdown: Option<ShutdownStep>,
+
+    qemu_pid: u32,
+    started: Instant,
+    ping_sent: Option<Instant>,
+    last_ping_rtt: Option<Duration>,
+
     input: Rc<RefCell<Input>>,
 
     x11_clipboard: UnboundedSender<ClipboardRequestResponse>,
@@ -64,9 +265,15 @@ pub struct Controller {
     x11_clipboard_reader: UnboundedSender<ClipboardType>,
     win_clipboard_request: Option<ClipboardRequestEvent>,
 
+    monitor: QmpClient,
     // write-only
-    monitor: UnboundedSender<QmpCommand>,
     clientpipe: UnboundedSender<GaCmdOut>,
+    // control clients that want to know what's going on
+    subscribers: Vec<UnboundedSender<ControlCmdOut>>,
+
+    handle: Handle,
+    // so replies we wait for can find their way back to us
+    me: Weak<RefCell<Controller>>,
 }
 
 impl Controller {
@@ -74,30 +281,120 @@ impl Controller {
         (&self.clientpipe).send(cmd.into()).unwrap();
     }
 
+    /// Runs `f` on this controller once QEMU replied to a command.
+    fn on_reply<R, F>(&self, reply: QmpFuture<R>, f: F)
+        where R: 'static, F: FnOnce(&mut Controller, Result<R, QmpError>) + 'static
+    {
+        let me = self.me.clone();
+        self.handle.spawn(reply.then(move |res| {
+            if let Some(me) = me.upgrade() {
+                f(&mut me.borrow_mut(), res);
+            }
+            Ok(())
+        }));
+    }
+
+    /// Registers a control client for all future events
+    pub fn subscribe(&mut self, sender: UnboundedSender<ControlCmdOut>) {
+        self.subscribers.push(sender);
+    }
+
+    /// Sends an event to all subscribers, forgetting about those that went away
+    pub fn emit(&mut self, event: ControlCmdOut) {
+        debug!("Event: {:?}", event);
+        self.subscribers.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    fn set_ga(&mut self, state: State) {
+        if self.ga != state {
+            self.ga = state;
+            self.emit(ControlCmdOut::GaStateChanged { state });
+        }
+    }
+
+    fn set_io_state(&mut self, state: IoState) {
+        let old = IoStatus::from(&self.io_state);
+        self.io_state = state;
+        let new = IoStatus::from(&self.io_state);
+        if old != new {
+            self.emit(ControlCmdOut::IoStateChanged { state: new });
+        }
+    }
+
     pub fn new(machine_config: MachineConfig,
-               monitor: UnboundedSender<QmpCommand>,
+               monitor: QmpClient,
                clientpipe: UnboundedSender<GaCmdOut>,
                input: Rc<RefCell<Input>>,
                x11_clipboard: UnboundedSender<ClipboardRequestResponse>,
                x11_clipboard_grabber: UnboundedSender<()>,
-               x11_clipboard_reader: UnboundedSender<ClipboardType>) -> Controller {
-        (&monitor).send(QmpCommand::QmpCapabilities).unwrap();
-        Controller {
+               x11_clipboard_reader: UnboundedSender<ClipboardType>,
+               qemu_pid: u32,
+               handle: &Handle) -> Rc<RefCell<Controller>> {
+        monitor.send(QmpCommand::QmpCapabilities);
+        let controller = Rc::new(RefCell::new(Controller {
             machine_config,
 
             ga: State::Down,
             io_state: IoState::Detached,
             suspend_senders: Vec::new(),
 
+            usb: UsbDevices::default(),
+            detach_generation: 0,
+            shutdown: None,
+
+            qemu_pid,
+            started: Instant::now(),
+            ping_sent: None,
+            last_ping_rtt: None,
+
             monitor,
             clientpipe,
+            subscribers: Vec::new(),
             input,
 
             x11_clipboard,
             x11_clipboard_grabber,
             x11_clipboard_reader,
             win_clipboard_request: None,
-        }
+
+            handle: handle.clone(),
+            me: Weak::new(),
+        }));
+        controller.borrow_mut().me = Rc::downgrade(&controller);
+        controller.borrow().check_qmp_commands();
+        controller.borrow().pin_cpus();
+        controller
+    }
+
+    /// Pins the vCPU threads as configured, QEMU created them by the time it talks QMP
+    fn pin_cpus(&self) {
+        let pinning = match self.machine_config.cpu_pinning {
+            Some(ref x) => x.clone(),
+            None => return,
+        };
+        let qemu_pid = self.qemu_pid;
+        let reply = self.monitor.execute(QmpCommand::QueryCpusFast);
+        self.on_reply(reply, move |_, res: Result<Vec<CpuInfoFast>, QmpError>| match res {
+            Ok(vcpus) => pinning::apply(&pinning, qemu_pid, &vcpus),
+            Err(e) => error!("Failed to query vCPU threads, not pinning them: {}", e),
+        });
+    }
+
+    /// Complains about QMP commands we rely on but this QEMU doesn't know
+    fn check_qmp_commands(&self) {
+        let reply = self.monitor.execute(QmpCommand::QueryCommands);
+        self.on_reply(reply, |_, res: Result<Vec<CommandInfo>, QmpError>| match res {
+            Ok(commands) => {
+                let missing: Vec<_> = REQUIRED_QMP_COMMANDS.iter().cloned()
+                    .filter(|x| !commands.iter().any(|c| c.name == *x))
+                    .collect();
+                if !missing.is_empty() {
+                    error!("QEMU does not support these QMP commands, expect things to break: {}",
+                           missing.join(", "));
+                }
+            }
+            Err(e) => warn!("Failed to query QMP commands: {}", e),
+        });
     }
 
     pub fn ga_ping(&mut self) -> bool {
@@ -106,16 +403,12 @@ impl Controller {
             State::Pinging => {
                 // the last ping wasn't even answered
                 // we conclude that the ga has died
-                self.ga = State::Down;
-                match self.io_state {
-                    IoState::FullEntry => self.io_detach(),
-                    IoState::TemporaryLightEntry(_) => self.temporary_exit(),
-                    _ => ()
-                }
+                self.ga_died();
                 false
             }
             State::Up => {
-                self.ga = State::Pinging;
+                self.set_ga(State::Pinging);
+                self.ping_sent = Some(Instant::now());
                 self.write_ga(GaCmdOut::Ping(()));
                 true
             }
@@ -123,13 +416,49 @@ impl Controller {
         }
     }
 
+    /// The GA stopped answering or its connection went away.
+    ///
+    /// A suspended Windows doesn't run its GA anyway, so that doesn't count.
+    pub fn ga_died(&mut self) {
+        match self.ga {
+            State::Up | State::Pinging => (),
+            _ => return,
+        }
+        self.set_ga(State::Down);
+        self.emit(ControlCmdOut::GaDied);
+        match self.io_state {
+            IoState::FullEntry => { let _ = self.io_detach(); }
+            IoState::TemporaryLightEntry(_) => self.temporary_exit(),
+            _ => ()
+        }
+    }
+
     pub fn ga_pong(&mut self) {
         if self.ga == State::Pinging {
-            self.ga = State::Up;
+            self.set_ga(State::Up);
+            self.last_ping_rtt = self.ping_sent.take().map(|x| x.elapsed());
         }
     }
 
-    pub fn ga_hello(&mut self) -> bool {
+    pub fn status(&self) -> Status {
+        let mut usb_attached: Vec<_> = self.machine_config.usb_devices.iter().enumerate()
+            .map(|(i, dev)| AttachedUsbDevice { id: format!("usb{}", i), binding: dev.binding.clone() })
+            .filter(|dev| self.usb.attached.contains(&dev.id))
+            .collect();
+        usb_attached.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Status {
+            ga: self.ga,
+            io: IoStatus::from(&self.io_state),
+            usb_attached,
+            qemu_pid: self.qemu_pid,
+            uptime_secs: self.started.elapsed().as_secs(),
+            ga_ping_rtt_ms: self.last_ping_rtt
+                .map(|x| x.as_secs() * 1000 + x.subsec_nanos() as u64 / 1_000_000),
+        }
+    }
+
+    pub fn ga_hello(&mut self) {
         sd_notify::notify_systemd(true, "Ready");
 
         // send GA all hotkeys we want to register
@@ -144,35 +473,32 @@ impl Controller {
         // to our ping - if we're not careful we might end up timeouting the new GA
         // instance for missing a ping we sent before it even existed!
         //
-        // To handle this, we just make sure that there's no lingering ping AND
-        // we return false if we didn't notice the GA going down as the timer still
-        // exists in that case so it would be a bug to create a second one.
+        // To handle this, we make sure that there's no lingering ping here and the
+        // clientpipe restarts its ping timer.
 
-        let ga = mem::replace(&mut self.ga, State::Up);
+        self.emit(ControlCmdOut::GaHello);
+        let ga = self.ga;
+        self.set_ga(State::Up);
+        self.ping_sent = None;
 
         if let IoState::AwaitingUpgrade = self.io_state {
-            self.io_attach();
+            let _ = self.io_attach();
         }
 
-        match ga {
-            State::Pinging | State::Up => false,
-            State::Down | State::Suspended => true,
-            State::Resuming => {
-                self.io_attach();
-                true
-            }
-            State::Suspending => false, // wtf though
+        if let State::Resuming = ga {
+            let _ = self.io_attach();
         }
     }
 
     pub fn ga_suspending(&mut self) {
-        self.io_detach();
-        self.ga = State::Suspending;
+        let _ = self.io_detach();
+        self.set_ga(State::Suspending);
     }
 
     pub fn qemu_suspended(&mut self) {
         info!("Windows is now suspended");
-        self.ga = State::Suspended;
+        self.emit(ControlCmdOut::QemuSuspend);
+        self.set_ga(State::Suspended);
         for sender in self.suspend_senders.drain(..) {
             let _ = sender.send(());
         }
@@ -186,10 +512,13 @@ impl Controller {
                     info!("Got action-hotkey while in temporary light entry. Ignoring.");
                     return;
                 }
-                match action {
+                let res = match action {
                     Action::IoUpgrade => self.io_attach(),
                     Action::IoEntryForced => self.io_force_attach(),
                     Action::IoExit => self.io_detach(),
+                };
+                if let Err(e) = res {
+                    info!("Ignoring hotkey: {}", e);
                 }
             }
             Some(HotKeyAction::Exec(cmd)) => {
@@ -199,27 +528,35 @@ impl Controller {
     }
 
     /// Attaches all configured devices if GA is up and wakes the host up if it's suspended
-    pub fn io_attach(&mut self) {
+    pub fn io_attach(&mut self) -> Result<(), &'static str> {
         match self.ga {
-            State::Resuming | State::Suspending => (),
+            // we attach as soon as the GA is back anyway
+            State::Resuming => Ok(()),
+            State::Suspending => Err("Windows is suspending"),
             State::Suspended => {
                 // make them wake up
-                (&self.monitor).send(QmpCommand::SystemWakeup).unwrap();
+                self.monitor.send(QmpCommand::SystemWakeup);
                 // can't enter now - gotta wait for GA to get ready
-                self.ga = State::Resuming;
+                self.set_ga(State::Resuming);
+                Ok(())
+            },
+            State::Down => match self.io_state {
+                IoState::FullEntry => Err("io is already attached"),
+                IoState::AwaitingUpgrade => Err("already waiting for the guest agent"),
+                _ => {
+                    let _ = self.light_attach();
+                    self.set_io_state(IoState::AwaitingUpgrade);
+                    Ok(())
+                }
             },
-            State::Down => {
-                self.light_attach();
-                self.io_state = IoState::AwaitingUpgrade;
-            }
             State::Up | State::Pinging => self.io_force_attach(),
         }
     }
 
-    pub fn try_attach(&mut self) {
+    pub fn try_attach(&mut self) -> Result<(), &'static str> {
         match self.ga {
             State::Up | State::Pinging => self.io_force_attach(),
-            _ => (),
+            _ => Err("the guest agent is not running"),
         }
     }
 
@@ -228,8 +565,8 @@ impl Controller {
             State::Up | State::Pinging => match self.io_state {
                 IoState::Detached => {
                     self.write_ga(GaCmdOut::SetMousePosition(Point { x, y }));
-                    self.light_attach();
-                    self.io_state = IoState::TemporaryLightEntry(sender);
+                    let _ = self.light_attach();
+                    self.set_io_state(IoState::TemporaryLightEntry(sender));
                     true
                 }
                 _ => false
@@ -241,26 +578,28 @@ impl Controller {
     pub fn temporary_exit(&mut self) {
         // only detach if we are not already detached
         if let IoState::TemporaryLightEntry(_) = self.io_state {
-            self.io_detach();
+            let _ = self.io_detach();
         }
     }
 
-    pub fn light_attach(&mut self) {
+    pub fn light_attach(&mut self) -> Result<(), &'static str> {
         debug!("light entry");
 
         match self.io_state {
             IoState::Detached => {
                 self.prepare_entry();
                 self.input.borrow_mut().resume();
-                self.io_state = IoState::LightEntry;
+                self.set_io_state(IoState::LightEntry);
             }
-            IoState::AwaitingUpgrade => self.io_state = IoState::LightEntry,
-            IoState::LightEntry | IoState::FullEntry | IoState::TemporaryLightEntry(_) => (),
+            IoState::AwaitingUpgrade => self.set_io_state(IoState::LightEntry),
+            IoState::LightEntry | IoState::FullEntry | IoState::TemporaryLightEntry(_) =>
+                return Err("io is already attached"),
         }
+        Ok(())
     }
 
     /// Attaches all configured devices regardless of GA state
-    pub fn io_force_attach(&mut self) {
+    pub fn io_force_attach(&mut self) -> Result<(), &'static str> {
         debug!("full entry");
 
         // release light entry first so we don't mess things up
@@ -271,34 +610,45 @@ impl Controller {
                 self.input.borrow_mut().suspend();
                 sender.send(ControlCmdOut::TemporaryLightDetached).unwrap();
             }
-            IoState::FullEntry => return,
+            IoState::FullEntry => return Err("io is already attached"),
         }
 
         self.prepare_entry();
 
         let mut udev = Context::new().expect("Failed to create udev context");
 
-        let mut sorted = self.machine_config.usb_devices.iter().enumerate()
-            .sorted_by(|&(_, a), &(_, b)| a.bus.cmp(&b.bus));
-        let groups = sorted.drain(..).group_by(|&(_, dev)| dev.bus);
-        for (port, (i, dev)) in groups.into_iter().flat_map(|(_, group)| group.enumerate())
-                .filter(|&(_, (_, ref dev))| !dev.permanent) {
-            if let Some((hostbus, hostaddr)) = udev_resolve_binding(&mut udev, &dev.binding)
-                    .expect("Failed to resolve usb binding") {
-                let bus = dev.bus;
-                let usable_ports = util::usable_ports(bus);
-                (&self.monitor).send(QmpCommand::DeviceAdd {
-                    driver: "usb-host",
-                    bus: format!("{}{}.0", bus, port / usable_ports),
-                    port: (port % usable_ports) + 1,
-                    id: format!("usb{}", i),
-                    hostbus: hostbus,
-                    hostaddr: hostaddr,
-                }).unwrap();
+        let mut adds = Vec::new();
+        {
+            let mut sorted = self.machine_config.usb_devices.iter().enumerate()
+                .sorted_by(|&(_, a), &(_, b)| a.bus.cmp(&b.bus));
+            let groups = sorted.drain(..).group_by(|&(_, dev)| dev.bus);
+            for (port, (i, dev)) in groups.into_iter().flat_map(|(_, group)| group.enumerate())
+                    .filter(|&(_, (_, ref dev))| !dev.permanent) {
+                if let Some((hostbus, hostaddr)) = udev_resolve_binding(&mut udev, &dev.binding)
+                        .expect("Failed to resolve usb binding") {
+                    let bus = dev.bus;
+                    let usable_ports = util::usable_ports(bus);
+                    let id = format!("usb{}", i);
+                    adds.push((id.clone(), QmpCommand::DeviceAdd {
+                        driver: "usb-host",
+                        bus: format!("{}{}.0", bus, port / usable_ports),
+                        port: (port % usable_ports) + 1,
+                        id,
+                        hostbus: hostbus,
+                        hostaddr: hostaddr,
+                    }));
+                }
+            }
+        }
+
+        for (id, cmd) in adds {
+            if let Some(cmd) = self.usb.attach(id.clone(), cmd) {
+                self.usb_add(id, cmd);
             }
         }
 
-        self.io_state = IoState::FullEntry;
+        self.set_io_state(IoState::FullEntry);
+        Ok(())
     }
 
     pub fn prepare_entry(&mut self) {
@@ -324,38 +674,173 @@ impl Controller {
     }
 
     /// Detaches all configured devices
-    pub fn io_detach(&mut self) {
-        assert!(self.ga != State::Suspending, "trying to exit from a suspending vm?");
-        assert!(self.ga != State::Suspended, "trying to exit from a suspended vm?");
+    pub fn io_detach(&mut self) -> Result<(), &'static str> {
+        match self.ga {
+            State::Suspending => return Err("Windows is suspending"),
+            State::Suspended => return Err("Windows is suspended"),
+            _ => (),
+        }
 
         match self.io_state {
-            IoState::Detached => (),
+            IoState::Detached => return Err("io is not attached"),
             IoState::AwaitingUpgrade | IoState::LightEntry | IoState::TemporaryLightEntry(_) => {
                 debug!("detaching light entry");
                 self.input.borrow_mut().suspend();
-                (&self.monitor).send(QmpCommand::InputSendEvent {
+                self.monitor.send(QmpCommand::InputSendEvent {
                     events: Cow::from(RELEASE_ALL_KEYS),
-                }).unwrap();
+                });
             },
             IoState::FullEntry => {
                 debug!("detaching full entry");
-                for i in self.machine_config.usb_devices.iter().enumerate()
-                        .filter(|&(_, dev)| !dev.permanent).map(|(i, _)| i) {
-                    (&self.monitor).send(QmpCommand::DeviceDel { id: format!("usb{}", i) }).unwrap();
+                for id in self.usb.detach_all() {
+                    let reply = self.monitor.execute(QmpCommand::DeviceDel { id: id.clone() });
+                    self.on_reply(reply, move |controller, res| controller.usb_deleted(id, res));
+                }
+
+                if !self.usb.detaching.is_empty() {
+                    self.detach_generation += 1;
+                    let generation = self.detach_generation;
+                    let me = self.me.clone();
+                    let timeout = Timeout::new(Duration::from_secs(DEVICE_DELETE_TIMEOUT), &self.handle)
+                        .expect("Failed to create timeout");
+                    self.handle.spawn(timeout.then(move |_| {
+                        if let Some(me) = me.upgrade() {
+                            me.borrow_mut().usb_detach_timeout(generation);
+                        }
+                        Ok(())
+                    }));
                 }
             }
         }
 
-        self.io_state = IoState::Detached;
+        self.set_io_state(IoState::Detached);
+        Ok(())
+    }
+
+    fn usb_add(&mut self, id: String, cmd: QmpCommand) {
+        let reply = self.monitor.execute(cmd);
+        self.on_reply(reply, move |controller, res| controller.usb_added(id, res));
+    }
+
+    fn usb_added(&mut self, id: String, res: Result<Ret, QmpError>) {
+        match res {
+            Ok(Ret {}) => debug!("Attached {}", id),
+            Err(e) => {
+                error!("Failed to attach {} to Windows: {}", id, e);
+                self.usb.attach_failed(&id);
+            }
+        }
+    }
+
+    fn usb_deleted(&mut self, id: String, res: Result<Ret, QmpError>) {
+        match res {
+            Ok(Ret {}) => debug!("Requested removal of {}", id),
+            Err(e) => {
+                // QEMU won't send DEVICE_DELETED for this one, so stop waiting
+                error!("Failed to detach {} from Windows: {}", id, e);
+                self.usb_device_gone(&id);
+            }
+        }
+    }
+
+    /// QEMU reports that a device is fully removed from the guest
+    pub fn qemu_device_deleted(&mut self, id: &str) {
+        if self.usb.detaching.contains(id) {
+            debug!("{} was released by Windows", id);
+            self.usb_device_gone(id);
+        }
+    }
+
+    fn usb_device_gone(&mut self, id: &str) {
+        if let Some(cmd) = self.usb.gone(id) {
+            self.usb_add(id.to_owned(), cmd);
+        }
+    }
+
+    fn usb_detach_timeout(&mut self, generation: u64) {
+        if generation != self.detach_generation || self.usb.detaching.is_empty() {
+            // either all went well or a newer detach is responsible now
+            return;
+        }
+
+        error!("Windows did not release these USB devices within {} seconds:", DEVICE_DELETE_TIMEOUT);
+        for (i, dev) in self.machine_config.usb_devices.iter().enumerate() {
+            if self.usb.detaching.contains(&format!("usb{}", i)) {
+                error!("\tusb{}: {:?}", i, dev.binding);
+            }
+        }
+        // waiting any longer would keep them from ever being attached again
+        for (id, cmd) in self.usb.give_up() {
+            info!("Attaching {} anyway", id);
+            self.usb_add(id, cmd);
+        }
     }
 
+    /// Shuts Windows down, escalating up to SIGKILL if it doesn't comply in time
     pub fn shutdown(&mut self) {
-        (&self.monitor).send(QmpCommand::SystemPowerdown).unwrap();
+        if let Some(step) = self.shutdown {
+            info!("Already shutting down ({:?})", step);
+            return;
+        }
+
+        let step = ShutdownStep::first(self.ga);
+        self.shutdown_step(step);
+    }
+
+    fn shutdown_step(&mut self, step: ShutdownStep) {
+        info!("{}", step.status());
+        sd_notify::notify_stopping(step.status());
+        self.shutdown = Some(step);
+        self.emit(ControlCmdOut::ShutdownStep { step });
+
+        let timeout = match step {
+            ShutdownStep::GuestAgent => {
+                self.write_ga(GaCmdOut::Shutdown(()));
+                self.machine_config.shutdown_timeout()
+            }
+            ShutdownStep::Acpi => {
+                self.monitor.send(QmpCommand::SystemPowerdown);
+                self.machine_config.shutdown_timeout()
+            }
+            ShutdownStep::Quit => {
+                self.monitor.send(QmpCommand::Quit);
+                QUIT_TIMEOUT
+            }
+            ShutdownStep::Kill => {
+                // QEMU leads its own process group, so helpers it spawned go down with it
+                let pid = self.qemu_pid as libc::pid_t;
+                if unsafe { libc::kill(-pid, libc::SIGKILL) } != 0 {
+                    warn!("Failed to kill QEMU's process group, killing QEMU only");
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                }
+                return;
+            }
+        };
+
+        let me = self.me.clone();
+        let timeout = Timeout::new(Duration::from_secs(timeout), &self.handle).expect("Failed to create timeout");
+        self.handle.spawn(timeout.then(move |_| {
+            if let Some(me) = me.upgrade() {
+                me.borrow_mut().shutdown_timed_out(step);
+            }
+            Ok(())
+        }));
+    }
+
+    fn shutdown_timed_out(&mut self, step: ShutdownStep) {
+        if self.shutdown != Some(step) {
+            return;
+        }
+        if let Some(next) = step.next() {
+            warn!("Step {:?} of shutting down timed out", step);
+            self.shutdown_step(next);
+        }
     }
 
     /// Windows told us to grab the keyboard
     pub fn grab_x11_clipboard(&mut self) {
         (&self.x11_clipboard_grabber).send(()).unwrap();
+        self.emit(ControlCmdOut::ClipboardOwnerChanged { owner: ClipboardOwner::Windows });
     }
 
     /// Paste on Windows, so we have to request contents
@@ -380,6 +865,7 @@ impl Controller {
     /// We lost the X11 clipboard, so we grab the Windows keyboard
     pub fn grab_win_clipboard(&mut self) {
         self.write_ga(ClipboardMessage::GrabClipboard(()));
+        self.emit(ControlCmdOut::ClipboardOwnerChanged { owner: ClipboardOwner::Linux });
     }
 
     /// Paste on Linux, so we have to request contents
@@ -462,3 +948,130 @@ pub fn resolve_binding(binding: &UsbBinding) -> UdevResult<Option<(String, Strin
     let udev = Context::new().expect("Failed to create udev context");
     udev_resolve_binding(&udev, binding)
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use futures::unsync::mpsc::{self, UnboundedReceiver};
+    use input::event::Event;
+    use monitor::Monitor;
+
+    /// Whatever a test controller talks to, dropping it would make the controller panic
+    pub struct Fixture {
+        pub monitor: Monitor,
+        pub qemu: UnixStream,
+        pub ga: UnboundedReceiver<GaCmdOut>,
+        _input: UnboundedReceiver<Event>,
+        _clipboard: (UnboundedReceiver<ClipboardRequestResponse>, UnboundedReceiver<()>,
+                     UnboundedReceiver<ClipboardType>),
+    }
+
+    /// A controller without USB devices, with a socket pair standing in for QEMU
+    pub fn controller(handle: &Handle) -> (Rc<RefCell<Controller>>, Fixture) {
+        let (ours, qemu) = UnixStream::pair().unwrap();
+        let mut monitor = Monitor::new(ours, handle);
+        let (ga_send, ga) = mpsc::unbounded();
+        let (input, input_events) = Input::new(handle, MachineConfig::default());
+        let (clip_send, clip_recv) = mpsc::unbounded();
+        let (grab_send, grab_recv) = mpsc::unbounded();
+        let (read_send, read_recv) = mpsc::unbounded();
+        let controller = Controller::new(MachineConfig::default(), monitor.take_client(), ga_send,
+                                         Rc::new(RefCell::new(input)), clip_send, grab_send, read_send, 0, handle);
+        (controller, Fixture {
+            monitor,
+            qemu,
+            ga,
+            _input: input_events,
+            _clipboard: (clip_recv, grab_recv, read_recv),
+        })
+    }
+
+    #[test]
+    fn shutdown_first_step() {
+        assert_eq!(ShutdownStep::first(State::Up), ShutdownStep::GuestAgent);
+        assert_eq!(ShutdownStep::first(State::Pinging), ShutdownStep::GuestAgent);
+        assert_eq!(ShutdownStep::first(State::Down), ShutdownStep::Acpi);
+        assert_eq!(ShutdownStep::first(State::Resuming), ShutdownStep::Acpi);
+        assert_eq!(ShutdownStep::first(State::Suspending), ShutdownStep::Quit);
+        assert_eq!(ShutdownStep::first(State::Suspended), ShutdownStep::Quit);
+    }
+
+    #[test]
+    fn shutdown_escalation() {
+        let mut steps = vec![ShutdownStep::GuestAgent];
+        while let Some(next) = steps.last().unwrap().next() {
+            steps.push(next);
+        }
+        assert_eq!(steps, vec![ShutdownStep::GuestAgent, ShutdownStep::Acpi, ShutdownStep::Quit, ShutdownStep::Kill]);
+    }
+
+    fn add(id: &str) -> QmpCommand {
+        QmpCommand::DeviceAdd {
+            driver: "usb-host",
+            id: id.to_owned(),
+            bus: "xhci0.0".to_owned(),
+            port: 1,
+            hostbus: "1".to_owned(),
+            hostaddr: "2".to_owned(),
+        }
+    }
+
+    /// Which device a device_add we got to send is for
+    fn sent(cmd: Option<QmpCommand>) -> Option<String> {
+        match cmd {
+            Some(QmpCommand::DeviceAdd { id, .. }) => Some(id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn usb_deferred_attach() {
+        let mut usb = UsbDevices::default();
+        assert_eq!(sent(usb.attach("usb0".to_owned(), add("usb0"))), Some("usb0".to_owned()));
+        assert_eq!(sent(usb.attach("usb1".to_owned(), add("usb1"))), Some("usb1".to_owned()));
+        assert_eq!(usb.detach_all(), vec!["usb0", "usb1"]);
+        assert!(usb.attached.is_empty());
+
+        // attached again before Windows let go of it
+        assert_eq!(sent(usb.attach("usb0".to_owned(), add("usb0"))), None);
+        assert!(!usb.attached.contains("usb0"));
+        assert_eq!(sent(usb.gone("usb1")), None);
+        assert_eq!(sent(usb.gone("usb0")), Some("usb0".to_owned()));
+        assert!(usb.attached.contains("usb0"));
+        assert!(usb.detaching.is_empty());
+        // QEMU telling us twice doesn't add it twice
+        assert_eq!(sent(usb.gone("usb0")), None);
+
+        usb.attach_failed("usb0");
+        assert!(usb.attached.is_empty());
+    }
+
+    #[test]
+    fn usb_detach_while_deferred() {
+        let mut usb = UsbDevices::default();
+        usb.attach("usb0".to_owned(), add("usb0"));
+        usb.detach_all();
+        assert_eq!(sent(usb.attach("usb0".to_owned(), add("usb0"))), None);
+        // the deferred device never made it to Windows, so there's nothing to remove
+        assert!(usb.detach_all().is_empty());
+        assert_eq!(sent(usb.gone("usb0")), None);
+        assert!(usb.attached.is_empty());
+    }
+
+    #[test]
+    fn usb_detach_timeout() {
+        let mut usb = UsbDevices::default();
+        usb.attach("usb0".to_owned(), add("usb0"));
+        usb.attach("usb1".to_owned(), add("usb1"));
+        usb.detach_all();
+        usb.attach("usb1".to_owned(), add("usb1"));
+        let retried: Vec<_> = usb.give_up().into_iter().map(|(id, cmd)| (id, sent(Some(cmd)))).collect();
+        assert_eq!(retried, vec![("usb1".to_owned(), Some("usb1".to_owned()))]);
+        assert!(usb.detaching.is_empty());
+        assert!(usb.attached.contains("usb1"));
+        // a late DEVICE_DELETED changes nothing
+        assert_eq!(sent(usb.gone("usb0")), None);
+        assert_eq!(usb.attached.len(), 1);
+    }
+}
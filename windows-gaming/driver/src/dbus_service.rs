@@ -0,0 +1,288 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::Error;
+
+use libdbus::{Connection, ConnectionItem, BusType, Message, MessageItem, NameFlag};
+use libdbus::tree::{Factory, EmitsChangedSignal, MethodErr};
+use tokio_core::reactor::Handle;
+use futures::{future, Stream, Future};
+use futures::unsync::mpsc;
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use controller::Controller;
+use control::ControlCmdOut;
+use dbus::DBusItems;
+
+pub const NAME: &'static str = "org.windowsgaming.Driver1";
+pub const PATH: &'static str = "/org/windowsgaming/Driver1";
+pub const INTERFACE: &'static str = "org.windowsgaming.Driver1";
+
+/// Connects to the session bus, if there is one.
+///
+/// The system instance usually has none, so we just go without the service there.
+pub fn session_dbus() -> Option<Connection> {
+    match Connection::get_private(BusType::Session) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("Not exporting D-Bus service, failed to connect to the session bus: {:?}", e);
+            None
+        }
+    }
+}
+
+/// snake_case name of a state, the same as on the control socket
+fn state_name<T: Serialize>(state: &T) -> String {
+    match serde_json::to_value(state) {
+        Ok(Value::String(x)) => x,
+        x => panic!("state {:?} doesn't serialize to a string", x),
+    }
+}
+
+/// `PropertiesChanged` for one of our (string) properties
+fn properties_changed(name: &str, value: String) -> Message {
+    let changed = MessageItem::new_array(vec![MessageItem::DictEntry(
+        Box::new(name.into()),
+        Box::new(MessageItem::Variant(Box::new(value.into()))),
+    )]).unwrap();
+    let mut msg = Message::new_signal(PATH, "org.freedesktop.DBus.Properties", "PropertiesChanged").unwrap();
+    msg.append_items(&[INTERFACE.into(), changed, MessageItem::Array(Vec::new(), "as".into())]);
+    msg
+}
+
+enum Item {
+    Bus(ConnectionItem),
+    Event(ControlCmdOut),
+    // replies to methods that had to wait for Windows
+    Reply(Message),
+}
+
+/// Exports `org.windowsgaming.Driver1` on the given bus (if any) and serves it.
+pub fn create<'a>(bus: Option<&'a Connection>, controller: Rc<RefCell<Controller>>, handle: &'a Handle)
+                  -> Box<Future<Item = (), Error = Error> + 'a> {
+    let bus = match bus {
+        Some(x) => x,
+        None => return Box::new(future::ok(())),
+    };
+    if let Err(e) = bus.register_name(NAME, NameFlag::DoNotQueue as u32) {
+        warn!("Failed to acquire D-Bus name {}: {:?}", NAME, e);
+        return Box::new(future::ok(()));
+    }
+
+    let f = Factory::new_fn::<()>();
+    let (c1, c2, c3, c4, c5, c6) = (controller.clone(), controller.clone(), controller.clone(),
+                                    controller.clone(), controller.clone(), controller.clone());
+    let (reply_send, replies) = mpsc::unbounded();
+    let suspend_handle = handle.clone();
+    let iface = f.interface(INTERFACE, ())
+        .add_m(f.method("Attach", (), move |m| {
+            c1.borrow_mut().io_attach().map_err(|e| MethodErr::failed(&e))?;
+            Ok(vec![m.msg.method_return()])
+        }))
+        .add_m(f.method("Detach", (), move |m| {
+            c2.borrow_mut().io_detach().map_err(|e| MethodErr::failed(&e))?;
+            Ok(vec![m.msg.method_return()])
+        }))
+        // replies once Windows is suspended
+        .add_m(f.method("Suspend", (), move |m| {
+            let done = m.msg.method_return();
+            let failed = Message::new_error(m.msg, "org.freedesktop.DBus.Error.Failed",
+                                            "Windows did not finish suspending").unwrap();
+            let reply_send = reply_send.clone();
+            suspend_handle.spawn(c3.borrow_mut().suspend().then(move |res| {
+                let _ = (&reply_send).send(if res.is_ok() { done } else { failed });
+                Ok(())
+            }));
+            Ok(vec![])
+        }))
+        .add_m(f.method("Shutdown", (), move |m| {
+            c4.borrow_mut().shutdown();
+            Ok(vec![m.msg.method_return()])
+        }))
+        .add_p(f.property::<&str, _>("GaState", ())
+            .emits_changed(EmitsChangedSignal::True)
+            .on_get(move |i, _| {
+                i.append(state_name(&c5.borrow().status().ga));
+                Ok(())
+            }))
+        .add_p(f.property::<&str, _>("IoState", ())
+            .emits_changed(EmitsChangedSignal::True)
+            .on_get(move |i, _| {
+                i.append(state_name(&c6.borrow().status().io));
+                Ok(())
+            }))
+        .add_s(f.signal("GaStateChanged", ()).sarg::<&str, _>("state"))
+        .add_s(f.signal("IoStateChanged", ()).sarg::<&str, _>("state"))
+        .add_s(f.signal("GaHello", ()))
+        .add_s(f.signal("GaDied", ()));
+    let tree = f.tree(()).add(f.object_path(PATH, ()).introspectable().add(iface));
+    tree.set_registered(bus, true).expect("Failed to register D-Bus object");
+
+    let (send, events) = mpsc::unbounded();
+    controller.borrow_mut().subscribe(send);
+
+    let items = DBusItems::new(bus, handle).map(Item::Bus);
+    let events = events.map(Item::Event);
+    let replies = replies.map(Item::Reply);
+    debug!("Exported D-Bus service {}", NAME);
+
+    Box::new(items.select(events).select(replies).for_each(move |item| {
+        match item {
+            Item::Bus(ConnectionItem::MethodCall(ref msg)) => {
+                if let Some(replies) = tree.handle(msg) {
+                    for reply in replies {
+                        let _ = bus.send(reply);
+                    }
+                }
+            }
+            Item::Bus(_) => (),
+            Item::Event(event) => {
+                let signal = |name: &str| Message::new_signal(PATH, INTERFACE, name).unwrap();
+                let msgs = match event {
+                    ControlCmdOut::GaStateChanged { state } => vec![
+                        signal("GaStateChanged").append1(state_name(&state)),
+                        properties_changed("GaState", state_name(&state)),
+                    ],
+                    ControlCmdOut::IoStateChanged { state } => vec![
+                        signal("IoStateChanged").append1(state_name(&state)),
+                        properties_changed("IoState", state_name(&state)),
+                    ],
+                    ControlCmdOut::GaHello => vec![signal("GaHello")],
+                    ControlCmdOut::GaDied => vec![signal("GaDied")],
+                    _ => return Ok(()),
+                };
+                for msg in msgs {
+                    let _ = bus.send(msg);
+                }
+            }
+            Item::Reply(msg) => {
+                let _ = bus.send(msg);
+            }
+        }
+        Ok(())
+    }).then(|_| Ok(())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::thread;
+    use std::time::Duration;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Child, Stdio};
+    use futures::future::Either;
+    use futures::sync::oneshot;
+    use tokio_core::reactor::{Core, Timeout};
+    use controller;
+
+    /// A dbus-daemon of our own, so we neither need nor disturb the session of whoever runs the tests
+    struct PrivateBus(Child);
+
+    impl PrivateBus {
+        fn start() -> Option<PrivateBus> {
+            let mut daemon = match Command::new("dbus-daemon").args(&["--session", "--nofork", "--print-address"])
+                    .stdout(Stdio::piped()).spawn() {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("Skipping, can't start dbus-daemon: {}", e);
+                    return None;
+                }
+            };
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+            Some(PrivateBus(daemon))
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn call(bus: &Connection, iface: &str, method: &str, args: &[MessageItem]) -> Result<Message, String> {
+        let mut msg = Message::new_method_call(NAME, PATH, iface, method).unwrap();
+        msg.append_items(args);
+        bus.send_with_reply_and_block(msg, 5000)
+            .map_err(|e| e.message().unwrap_or("").to_owned())
+    }
+
+    fn string(item: &MessageItem) -> String {
+        match *item {
+            MessageItem::Str(ref x) => x.clone(),
+            MessageItem::Variant(ref x) => string(x),
+            ref x => panic!("not a string: {:?}", x),
+        }
+    }
+
+    /// Name and value of the first PropertiesChanged we get
+    fn next_change(bus: &Connection) -> (String, String) {
+        for item in bus.iter(5000) {
+            match item {
+                ConnectionItem::Signal(ref msg) => match &msg.get_items()[..] {
+                    &[MessageItem::Str(ref iface), MessageItem::Array(ref changed, _), _] if iface == INTERFACE =>
+                        match changed[0] {
+                            MessageItem::DictEntry(ref name, ref value) => return (string(name), string(value)),
+                            ref x => panic!("invalid PropertiesChanged: {:?}", x),
+                        },
+                    _ => (),
+                },
+                ConnectionItem::Nothing => panic!("no PropertiesChanged"),
+                _ => (),
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn service() {
+        let _daemon = match PrivateBus::start() {
+            Some(x) => x,
+            None => return,
+        };
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (controller, _fixture) = controller::test::controller(&handle);
+        let bus = session_dbus().unwrap();
+        let service = create(Some(&bus), controller.clone(), &handle);
+
+        // the client blocks on its calls, so it can't share the reactor with the service
+        let (done, client) = oneshot::channel();
+        thread::spawn(move || {
+            let bus = Connection::get_private(BusType::Session).unwrap();
+            bus.add_match("type='signal',interface='org.freedesktop.DBus.Properties'").unwrap();
+            let get = |name: &str| call(&bus, "org.freedesktop.DBus.Properties", "Get",
+                                        &[INTERFACE.into(), name.into()]).map(|x| string(&x.get_items()[0]));
+
+            assert_eq!(get("IoState").unwrap(), "detached");
+            assert_eq!(call(&bus, INTERFACE, "Detach", &[]).unwrap_err(), "io is not attached");
+
+            // without the GA this is a light entry waiting for the upgrade
+            call(&bus, INTERFACE, "Attach", &[]).unwrap();
+            assert_eq!(next_change(&bus), ("IoState".to_owned(), "light_entry".to_owned()));
+            assert_eq!(next_change(&bus), ("IoState".to_owned(), "awaiting_upgrade".to_owned()));
+            assert_eq!(get("IoState").unwrap(), "awaiting_upgrade");
+
+            // only returns once QEMU reported the suspend
+            call(&bus, INTERFACE, "Suspend", &[]).unwrap();
+            assert_eq!(next_change(&bus), ("GaState".to_owned(), "suspended".to_owned()));
+            assert_eq!(call(&bus, INTERFACE, "Detach", &[]).unwrap_err(), "Windows is suspended");
+            let _ = done.send(());
+        });
+
+        let ctrl = controller.clone();
+        let suspended = Timeout::new(Duration::from_millis(500), &handle).unwrap().then(move |_| {
+            ctrl.borrow_mut().qemu_suspended();
+            Ok(())
+        });
+        handle.spawn(suspended);
+
+        match core.run(service.select2(client)) {
+            Ok(Either::B(_)) => (),
+            _ => panic!("D-Bus client failed"),
+        }
+    }
+}
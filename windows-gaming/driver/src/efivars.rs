@@ -0,0 +1,38 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The pristine OVMF variable store shipped in the data directory.
+fn template(data: &Path) -> PathBuf {
+    data.join("ovmf-vars.fd")
+}
+
+/// The VM's persistent variable store in the state directory.
+pub fn path(state: &Path) -> PathBuf {
+    state.join("efivars.fd")
+}
+
+/// Returns the persistent efivars image, creating it from the template on first use.
+///
+/// Everything Windows writes to NVRAM (boot order, Secure Boot keys, ...) ends up in
+/// this file, so it must survive across runs.
+pub fn prepare(state: &Path, data: &Path) -> io::Result<PathBuf> {
+    let efivars = path(state);
+    if efivars.exists() {
+        debug!("Reusing efivars file {}", efivars.display());
+    } else {
+        fs::create_dir_all(state)?;
+        fs::copy(template(data), &efivars)?;
+        info!("Created efivars file {} from template", efivars.display());
+    }
+    Ok(efivars)
+}
+
+/// Overwrites the persistent efivars image with the pristine template.
+pub fn reset(state: &Path, data: &Path) -> io::Result<()> {
+    let efivars = path(state);
+    fs::create_dir_all(state)?;
+    fs::copy(template(data), &efivars)?;
+    info!("Reset efivars file {}", efivars.display());
+    Ok(())
+}
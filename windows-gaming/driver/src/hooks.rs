@@ -0,0 +1,345 @@
+//! Runs the configured pre_start and post_stop hooks around a VM session, and the event
+//! hooks while it's up.
+
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::os::unix::process::CommandExt as UnixCommandExt;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use libc;
+use futures::{future, Future, Stream};
+use futures::future::Either;
+use futures::unsync::mpsc;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_process::CommandExt;
+
+use common::config::{Config, Hook, Hooks, HookEvent};
+use common::util;
+use controller::{Controller, IoStatus};
+use control::ControlCmdOut;
+use serde_json::{self, Value};
+
+/// Every hook needs something to run, and pre_start names have to say which hook they are.
+pub fn validate(hooks: &Hooks) -> Result<(), String> {
+    for (phase, list) in &[("pre_start", &hooks.pre_start), ("post_stop", &hooks.post_stop)] {
+        if let Some(i) = list.iter().position(|x| x.command.is_empty()) {
+            return Err(format!("hooks.{}[{}] has no command", phase, i));
+        }
+    }
+    if let Some(i) = hooks.events.iter().position(|x| x.hook.command.is_empty()) {
+        return Err(format!("hooks.events[{}] has no command", i));
+    }
+
+    let mut names = HashSet::new();
+    if let Some(name) = hooks.pre_start.iter().filter_map(|x| x.name.as_ref()).find(|x| !names.insert(*x)) {
+        return Err(format!("hooks.pre_start has more than one hook named {}", name));
+    }
+    Ok(())
+}
+
+/// Describes the VM to the hooks
+fn environment(cfg: &Config, tmp: &Path, state: &Path) -> Vec<(&'static str, String)> {
+    let machine = &cfg.machine;
+    let pinning = machine.cpu_pinning.as_ref();
+    vec![
+        ("WINDOWS_GAMING_MEMORY", machine.memory.clone()),
+        ("WINDOWS_GAMING_VCPUS", machine.vcpus().to_string()),
+        ("WINDOWS_GAMING_VCPU_HOST_CPUS",
+         pinning.map(|x| util::cpu_ranges(&x.vcpus).join(",")).unwrap_or_default()),
+        ("WINDOWS_GAMING_EMULATOR_HOST_CPUS",
+         pinning.map(|x| util::cpu_ranges(&x.emulator).join(",")).unwrap_or_default()),
+        ("WINDOWS_GAMING_PCI_DEVICES",
+         machine.pci_devices.iter().map(|x| x.slot.as_str()).collect::<Vec<_>>().join(" ")),
+        ("WINDOWS_GAMING_RUNTIME_DIR", tmp.display().to_string()),
+        ("WINDOWS_GAMING_STATE_DIR", state.display().to_string()),
+    ]
+}
+
+/// Waits for the hook, killing it (and whatever it started) once its time is up.
+fn wait_timeout(child: &mut Child, timeout: u64) -> io::Result<Option<i32>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status.code().unwrap_or(-1)));
+        }
+        if start.elapsed() >= Duration::from_secs(timeout) {
+            unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn name(hook: &Hook) -> &str {
+    hook.name.as_ref().unwrap_or(&hook.command[0])
+}
+
+fn command(hook: &Hook, phase: &str, env: &[(&'static str, String)]) -> Command {
+    let mut cmd = Command::new(&hook.command[0]);
+    cmd.args(&hook.command[1..])
+        .envs(env.iter().map(|&(k, ref v)| (k, v)))
+        .env("WINDOWS_GAMING_HOOK_PHASE", phase)
+        .env("WINDOWS_GAMING_HOOK_NAME", hook.name.as_ref().map(|x| x.as_str()).unwrap_or(""))
+        .stdin(Stdio::null());
+    // own process group, so a timeout gets everything the hook started
+    cmd.before_exec(|| unsafe {
+        if libc::setpgid(0, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    });
+    cmd
+}
+
+fn run(hook: &Hook, phase: &str, env: &[(&'static str, String)]) -> Result<(), String> {
+    let name = name(hook);
+    info!("Running {} hook {}", phase, name);
+
+    let mut child = command(hook, phase, env).spawn().map_err(|e| format!("{} hook {}: failed to start: {}", phase, name, e))?;
+    match wait_timeout(&mut child, hook.timeout()) {
+        Ok(Some(0)) => Ok(()),
+        Ok(Some(code)) => Err(format!("{} hook {} failed with exit code {}", phase, name, code)),
+        Ok(None) => Err(format!("{} hook {} timed out after {}s", phase, name, hook.timeout())),
+        Err(e) => Err(format!("{} hook {}: failed to wait: {}", phase, name, e)),
+    }
+}
+
+/// Remembers which pre_start hooks completed, so post_stop knows what there is to undo.
+///
+/// Dropping it runs the post_stop hooks unless that already happened, so they run even if we panicked.
+pub struct Session<'a> {
+    hooks: &'a Hooks,
+    env: Vec<(&'static str, String)>,
+    completed: Vec<&'a str>,
+    stopped: bool,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(cfg: &'a Config, tmp: &Path, state: &Path) -> Session<'a> {
+        Session { hooks: &cfg.hooks, env: environment(cfg, tmp, state), completed: Vec::new(), stopped: false }
+    }
+
+    /// Runs the pre_start hooks in order, stopping at the first one that fails.
+    pub fn pre_start(&mut self) -> Result<(), String> {
+        let hooks = self.hooks;
+        for hook in &hooks.pre_start {
+            run(hook, "pre_start", &self.env)?;
+            if let Some(ref name) = hook.name {
+                self.completed.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// post_stop hooks undoing a pre_start hook that never completed have nothing to do
+    fn post_stop_hooks(&self) -> Vec<&'a Hook> {
+        let hooks = self.hooks;
+        let pre_start: HashSet<_> = hooks.pre_start.iter().filter_map(|x| x.name.as_ref()).collect();
+        hooks.post_stop.iter()
+            .filter(|hook| match hook.name {
+                Some(ref name) if pre_start.contains(name) => self.completed.contains(&name.as_str()),
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Runs the post_stop hooks in order. Failures are only logged, the rest still gets cleaned up.
+    pub fn post_stop(&mut self) {
+        if self.stopped {
+            return;
+        }
+        self.stopped = true;
+        for hook in self.post_stop_hooks() {
+            if let Err(e) = run(hook, "post_stop", &self.env) {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Session<'a> {
+    fn drop(&mut self) {
+        self.post_stop();
+    }
+}
+
+/// The hook event a controller event counts as, if any
+fn hook_event(event: &ControlCmdOut) -> Option<HookEvent> {
+    match *event {
+        ControlCmdOut::IoStateChanged { state: IoStatus::FullEntry } => Some(HookEvent::FullEntry),
+        ControlCmdOut::IoStateChanged { state: IoStatus::LightEntry } |
+        ControlCmdOut::IoStateChanged { state: IoStatus::TemporaryLightEntry } => Some(HookEvent::LightEntry),
+        ControlCmdOut::IoStateChanged { state: IoStatus::Detached } => Some(HookEvent::Detach),
+        ControlCmdOut::GaHello => Some(HookEvent::GaUp),
+        ControlCmdOut::GaDied => Some(HookEvent::GaDown),
+        ControlCmdOut::QemuSuspend => Some(HookEvent::Suspend),
+        ControlCmdOut::QemuWakeup => Some(HookEvent::Resume),
+        ControlCmdOut::ClipboardOwnerChanged { .. } => Some(HookEvent::ClipboardGrab),
+        _ => None,
+    }
+}
+
+/// Starts an event hook without waiting for it, killing it once its time is up.
+fn spawn_event_hook(hook: &Hook, env: &[(&'static str, String)], handle: &Handle) {
+    let name = name(hook).to_owned();
+    let child = match command(hook, "event", env).spawn_async(handle) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("event hook {}: failed to start: {}", name, e);
+            return;
+        }
+    };
+    let pid = child.id();
+    let timeout = Timeout::new(Duration::from_secs(hook.timeout()), handle).expect("Failed to create timeout");
+    let seconds = hook.timeout();
+    let reaper = handle.clone();
+    handle.spawn(child.select2(timeout).then(move |res| {
+        match res {
+            Ok(Either::A((status, _))) if status.success() => debug!("event hook {} done", name),
+            Ok(Either::A((status, _))) => error!("event hook {} failed with {}", name, status),
+            Ok(Either::B((_, child))) => {
+                error!("event hook {} timed out after {}s", name, seconds);
+                unsafe { libc::kill(-(pid as i32), libc::SIGKILL) };
+                reaper.spawn(child.then(|_| Ok(())));
+            }
+            Err(Either::A((e, _))) => error!("event hook {}: failed to wait: {}", name, e),
+            Err(Either::B((e, _))) => error!("event hook {}: timeout failed: {}", name, e),
+        }
+        Ok(())
+    }));
+}
+
+/// Runs the event hooks for everything the controller reports, with the event in
+/// `WINDOWS_GAMING_EVENT` and its details as JSON in `WINDOWS_GAMING_EVENT_JSON`.
+pub fn events<'a>(cfg: &'a Config, tmp: &Path, state: &Path, controller: Rc<RefCell<Controller>>,
+                  handle: &'a Handle) -> Box<Future<Item = (), Error = io::Error> + 'a> {
+    if cfg.hooks.events.is_empty() {
+        return Box::new(future::ok(()));
+    }
+
+    let (send, events) = mpsc::unbounded();
+    controller.borrow_mut().subscribe(send);
+    let env = environment(cfg, tmp, state);
+
+    Box::new(events.for_each(move |event| {
+        let kind = match hook_event(&event) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let mut env = env.clone();
+        env.push(("WINDOWS_GAMING_EVENT", event_name(kind)));
+        env.push(("WINDOWS_GAMING_EVENT_JSON", event.to_json()));
+        for hook in cfg.hooks.events.iter().filter(|x| x.on == kind) {
+            spawn_event_hook(&hook.hook, &env, handle);
+        }
+        Ok(())
+    }).then(|_| Ok(())))
+}
+
+/// snake_case name of the event, the same as in the config
+fn event_name(event: HookEvent) -> String {
+    match serde_json::to_value(event) {
+        Ok(Value::String(x)) => x,
+        x => panic!("event {:?} doesn't serialize to a string", x),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, fs, process};
+
+    fn hook(name: Option<&str>, command: &[&str]) -> Hook {
+        Hook {
+            name: name.map(str::to_owned),
+            command: command.iter().map(|&x| x.to_owned()).collect(),
+            timeout: Some(1),
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            hooks: Hooks {
+                pre_start: vec![
+                    hook(Some("governor"), &["true"]),
+                    hook(None, &["true"]),
+                    hook(Some("display-manager"), &["sh", "-c", "exit 3"]),
+                    hook(Some("modules"), &["true"]),
+                ],
+                post_stop: vec![
+                    hook(Some("modules"), &["true"]),
+                    hook(Some("display-manager"), &["true"]),
+                    hook(None, &["true"]),
+                    hook(Some("governor"), &["true"]),
+                    hook(Some("unrelated"), &["true"]),
+                ],
+                events: Vec::new(),
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let mut cfg = config();
+        assert!(validate(&cfg.hooks).is_ok());
+        cfg.hooks.post_stop.push(hook(None, &[]));
+        assert!(validate(&cfg.hooks).is_err());
+        cfg.hooks.post_stop.pop();
+        cfg.hooks.pre_start.push(hook(Some("governor"), &["true"]));
+        assert!(validate(&cfg.hooks).is_err());
+    }
+
+    #[test]
+    fn rollback() {
+        let cfg = config();
+        let mut session = Session::new(&cfg, Path::new("/tmp"), Path::new("/tmp"));
+        assert_eq!(session.pre_start().unwrap_err(), "pre_start hook display-manager failed with exit code 3");
+        assert_eq!(session.completed, vec!["governor"]);
+        let names: Vec<_> = session.post_stop_hooks().into_iter().map(|x| x.name.as_ref()).collect();
+        assert_eq!(names, vec![None, Some(&"governor".to_owned()), Some(&"unrelated".to_owned())]);
+    }
+
+    #[test]
+    fn post_stop_once() {
+        let marker = env::temp_dir().join(format!("hooks-test-{}", process::id()));
+        let cfg = Config {
+            hooks: Hooks {
+                post_stop: vec![hook(None, &["sh", "-c", "echo >> \"$0\"", marker.to_str().unwrap()])],
+                ..Hooks::default()
+            },
+            ..Config::default()
+        };
+        {
+            let mut session = Session::new(&cfg, Path::new("/tmp"), Path::new("/tmp"));
+            session.post_stop();
+        }
+        // dropping a session we forgot to stop stops it all the same
+        drop(Session::new(&cfg, Path::new("/tmp"), Path::new("/tmp")));
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "\n\n");
+        fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    fn timeout() {
+        let start = Instant::now();
+        assert!(run(&hook(None, &["sleep", "10"]), "pre_start", &[]).unwrap_err().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(hook_event(&ControlCmdOut::IoStateChanged { state: IoStatus::TemporaryLightEntry }),
+                   Some(HookEvent::LightEntry));
+        assert_eq!(hook_event(&ControlCmdOut::IoStateChanged { state: IoStatus::AwaitingUpgrade }), None);
+        assert_eq!(hook_event(&ControlCmdOut::QemuWakeup), Some(HookEvent::Resume));
+        assert_eq!(hook_event(&ControlCmdOut::MouseEdged { x: 0, y: 0 }), None);
+        assert_eq!(event_name(HookEvent::ClipboardGrab), "clipboard_grab");
+    }
+}
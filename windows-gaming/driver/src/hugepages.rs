@@ -0,0 +1,209 @@
+//! Reserves hugepages for guest memory before QEMU starts and gives them back afterwards.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+
+use common::config::{MachineConfig, HugepageConfig, PageSize};
+use common::util;
+
+/// Pages we added to a pool, given back once this is dropped (QEMU is down or we panicked)
+pub struct Reservation {
+    pool: PathBuf,
+    added: u64,
+}
+
+fn pool_dir(size: PageSize, numa_node: Option<u32>) -> PathBuf {
+    let pool = format!("hugepages-{}kB", size.bytes() >> 10);
+    match numa_node {
+        Some(node) => Path::new("/sys/devices/system/node").join(format!("node{}", node)).join("hugepages").join(pool),
+        None => Path::new("/sys/kernel/mm/hugepages").join(pool),
+    }
+}
+
+fn read_count(path: &Path) -> io::Result<u64> {
+    let mut s = String::new();
+    File::open(path)?.read_to_string(&mut s)?;
+    s.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid count {:?}", s)))
+}
+
+fn write_count(path: &Path, count: u64) -> io::Result<()> {
+    File::create(path)?.write_all(count.to_string().as_bytes())
+}
+
+fn pages_needed(memory: u64, size: PageSize) -> u64 {
+    (memory + size.bytes() - 1) / size.bytes()
+}
+
+/// Page size of the hugetlbfs mounted at `path`, according to the contents of `/proc/mounts`
+fn hugetlbfs_pagesize(mounts: &str, path: &Path) -> Option<u64> {
+    mounts.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 4 && Path::new(fields[1]) == path && fields[2] == "hugetlbfs")
+        .filter_map(|fields| fields[3].split(',').find(|x| x.starts_with("pagesize="))
+                    .and_then(|x| util::parse_size(&x["pagesize=".len()..], 1)))
+        .last()
+}
+
+fn check_mount(cfg: &HugepageConfig) -> Result<(), String> {
+    let mut mounts = String::new();
+    File::open("/proc/mounts").and_then(|mut f| f.read_to_string(&mut mounts))
+        .map_err(|e| format!("Failed to read /proc/mounts: {}", e))?;
+    match hugetlbfs_pagesize(&mounts, Path::new(cfg.mount())) {
+        Some(x) if x == cfg.size.bytes() => Ok(()),
+        Some(x) => Err(format!("{} is a hugetlbfs with {} KiB pages, but {} pages are configured",
+                               cfg.mount(), x >> 10, cfg.size)),
+        None => Err(format!("{} is not a hugetlbfs, mount it with `mount -t hugetlbfs -o pagesize={} none {}`",
+                            cfg.mount(), cfg.size, cfg.mount())),
+    }
+}
+
+/// Asks the kernel to defragment memory so large pages are more likely to be available
+fn compact_memory() {
+    if let Err(e) = File::create("/proc/sys/vm/compact_memory").and_then(|mut f| f.write_all(b"1")) {
+        warn!("Failed to compact memory: {}", e);
+    }
+}
+
+/// How much guest memory has to come from which host node (`None` for any)
+fn requirements(machine: &MachineConfig, cfg: &HugepageConfig) -> BTreeMap<Option<u32>, u64> {
+    let mut memory = BTreeMap::new();
+    if machine.numa_nodes.is_empty() {
+        memory.insert(cfg.numa_node, machine.memory_bytes().unwrap_or(0));
+    }
+    for node in &machine.numa_nodes {
+        *memory.entry(node.host_node).or_insert(0) += node.memory_bytes().unwrap_or(0);
+    }
+    memory
+}
+
+/// Reserves hugepages for all guest memory if it's backed by hugepages at all.
+pub fn reserve_all(machine: &MachineConfig) -> Result<Vec<Reservation>, String> {
+    let cfg = match machine.hugepages() {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    check_mount(&cfg)?;
+
+    let mut reservations = Vec::new();
+    for (numa_node, memory) in requirements(machine, &cfg) {
+        // on failure, dropping the others gives their pages back
+        reservations.push(reserve(&cfg, numa_node, memory)?);
+    }
+    Ok(reservations)
+}
+
+/// Makes sure enough free hugepages for `memory` bytes of guest memory exist on the given node.
+fn reserve(cfg: &HugepageConfig, numa_node: Option<u32>, memory: u64) -> Result<Reservation, String> {
+    let pool = pool_dir(cfg.size, numa_node);
+    if !pool.exists() {
+        return Err(match numa_node {
+            Some(node) => format!("There are no {} hugepages on NUMA node {} ({} doesn't exist)",
+                                  cfg.size, node, pool.display()),
+            None => format!("This host doesn't support {} hugepages ({} doesn't exist)", cfg.size, pool.display()),
+        });
+    }
+    let nr_path = pool.join("nr_hugepages");
+    let needed = pages_needed(memory, cfg.size);
+    let free = read_count(&pool.join("free_hugepages"))
+        .map_err(|e| format!("Failed to read free {} hugepages: {}", cfg.size, e))?;
+    if free >= needed {
+        debug!("{} of the {} free {} hugepages are enough", needed, free, cfg.size);
+        return Ok(Reservation { pool, added: 0 });
+    }
+
+    let missing = needed - free;
+    let before = read_count(&nr_path).map_err(|e| format!("Failed to read {}: {}", nr_path.display(), e))?;
+    compact_memory();
+    write_count(&nr_path, before + missing)
+        .map_err(|e| format!("Failed to reserve {} hugepages through {}: {}", missing, nr_path.display(), e))?;
+
+    let after = read_count(&nr_path).map_err(|e| format!("Failed to read {}: {}", nr_path.display(), e))?;
+    if after < before + missing {
+        // a partial reservation is of no use to anyone
+        if let Err(e) = write_count(&nr_path, before) {
+            warn!("Failed to release the {} hugepages we did get: {}", after.saturating_sub(before), e);
+        }
+        let node = numa_node.map(|x| format!(" on NUMA node {}", x)).unwrap_or_default();
+        return Err(format!("Only got {} of the {} {} hugepages needed for {} MiB of guest memory{}, \
+                            the host doesn't have enough contiguous free memory",
+                           free + after.saturating_sub(before), needed, cfg.size, memory >> 20, node));
+    }
+    info!("Reserved {} {} hugepages", missing, cfg.size);
+    Ok(Reservation { pool, added: missing })
+}
+
+impl Drop for Reservation {
+    /// Gives the pages we reserved back to the kernel.
+    fn drop(&mut self) {
+        if self.added == 0 {
+            return;
+        }
+        let nr_path = self.pool.join("nr_hugepages");
+        let res = read_count(&nr_path).and_then(|nr| write_count(&nr_path, nr.saturating_sub(self.added)));
+        match res {
+            Ok(()) => info!("Released {} hugepages", self.added),
+            Err(e) => error!("Failed to release {} hugepages through {}: {}", self.added, nr_path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, fs, process};
+    use common::config::NumaNode;
+
+    #[test]
+    fn pages() {
+        assert_eq!(pages_needed(8 << 30, PageSize::Size1G), 8);
+        assert_eq!(pages_needed((8 << 30) + 1, PageSize::Size1G), 9);
+        assert_eq!(pages_needed(8 << 30, PageSize::Size2M), 4096);
+    }
+
+    #[test]
+    fn pools() {
+        assert_eq!(pool_dir(PageSize::Size1G, None),
+                   Path::new("/sys/kernel/mm/hugepages/hugepages-1048576kB"));
+        assert_eq!(pool_dir(PageSize::Size2M, Some(1)),
+                   Path::new("/sys/devices/system/node/node1/hugepages/hugepages-2048kB"));
+    }
+
+    #[test]
+    fn per_node() {
+        let cfg = HugepageConfig { size: PageSize::Size1G, mount: None, numa_node: Some(1) };
+        let mut machine = MachineConfig { memory: "8G".to_owned(), ..MachineConfig::default() };
+        assert_eq!(requirements(&machine, &cfg).into_iter().collect::<Vec<_>>(), vec![(Some(1), 8 << 30)]);
+
+        machine.numa_nodes = vec![
+            NumaNode { vcpus: vec![0], memory: "2G".to_owned(), host_node: Some(0) },
+            NumaNode { vcpus: vec![1], memory: "4G".to_owned(), host_node: None },
+            NumaNode { vcpus: vec![2], memory: "2G".to_owned(), host_node: Some(0) },
+        ];
+        assert_eq!(requirements(&machine, &cfg).into_iter().collect::<Vec<_>>(),
+                   vec![(None, 4 << 30), (Some(0), 4 << 30)]);
+    }
+
+    #[test]
+    fn release() {
+        let pool = env::temp_dir().join(format!("hugepages-test-{}", process::id()));
+        fs::create_dir_all(&pool).unwrap();
+        write_count(&pool.join("nr_hugepages"), 10).unwrap();
+        drop(Reservation { pool: pool.clone(), added: 4 });
+        assert_eq!(read_count(&pool.join("nr_hugepages")).unwrap(), 6);
+        drop(Reservation { pool: pool.clone(), added: 0 });
+        assert_eq!(read_count(&pool.join("nr_hugepages")).unwrap(), 6);
+        fs::remove_dir_all(&pool).unwrap();
+    }
+
+    #[test]
+    fn mounts() {
+        let mounts = "proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n\
+                      hugetlbfs /dev/hugepages hugetlbfs rw,relatime,pagesize=2M 0 0\n\
+                      none /dev/hugepages_vfio_1G hugetlbfs rw,relatime,pagesize=1024M 0 0\n";
+        assert_eq!(hugetlbfs_pagesize(mounts, Path::new("/dev/hugepages")), Some(2 << 20));
+        assert_eq!(hugetlbfs_pagesize(mounts, Path::new("/dev/hugepages_vfio_1G/")), Some(1 << 30));
+        assert_eq!(hugetlbfs_pagesize(mounts, Path::new("/proc")), None);
+    }
+}
@@ -4,6 +4,7 @@ extern crate libudev;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 extern crate libc;
 #[macro_use]
@@ -27,7 +28,8 @@ extern crate prost;
 extern crate common;
 
 pub mod qemu;
-pub use control::ControlCmdIn;
+pub use control::{ControlCmdIn, ControlCmdOut, ControlError, send_command, subscribe};
+pub use controller::{Status, ShutdownStep};
 
 mod control;
 mod monitor;
@@ -36,8 +38,17 @@ mod controller;
 mod my_io;
 mod sd_notify;
 mod samba;
+mod efivars;
+mod capabilities;
+mod cmdline;
+mod pinning;
+mod hugepages;
+mod numa;
+mod vfio;
+mod hooks;
 mod dbus;
 mod sleep_inhibitor;
+mod dbus_service;
 mod libinput;
 mod clipboard;
 mod release_all_keys;
@@ -48,7 +59,6 @@ use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream};
 use std::fs::{self, Permissions};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
-use std::process::Command;
 use std::io::ErrorKind;
 
 use tokio_core::reactor::Core;
@@ -61,25 +71,109 @@ use common::config::Config;
 use controller::Controller;
 use monitor::Monitor;
 use clientpipe::Clientpipe;
+use capabilities::Capabilities;
 use libinput::Input;
 use clipboard::X11Clipboard;
 
-pub fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
-    let control_socket_file = tmp.join("control.sock");
-    // first check for running sessions
-    match UnixStream::connect(&control_socket_file) {
+fn is_running(control_socket_file: &Path) -> bool {
+    match UnixStream::connect(control_socket_file) {
         Err(e) => match e.kind() {
-            ErrorKind::ConnectionRefused => (), // previous instance existed but is down now
-            ErrorKind::NotFound => (), // no previous instance
-            _ => warn!("Error while checking for running instances: {:?}", e), // ??? (but continue anyway)
+            ErrorKind::ConnectionRefused => false, // previous instance existed but is down now
+            ErrorKind::NotFound => false, // no previous instance
+            _ => {
+                warn!("Error while checking for running instances: {:?}", e); // ??? (but continue anyway)
+                false
+            }
         },
-        Ok(_) => {
-            error!("An instance of windows-gaming is already running in this runtime directory.");
-            error!("Either quit that or select a different runtime directory.");
+        Ok(_) => true,
+    }
+}
+
+/// Restores the VM's EFI variables to the pristine OVMF template.
+pub fn reset_nvram(tmp: &Path, state: &Path, data: &Path) {
+    if is_running(&tmp.join("control.sock")) {
+        error!("An instance of windows-gaming is currently running in this runtime directory.");
+        error!("Shut it down before resetting its NVRAM.");
+        return;
+    }
+
+    efivars::reset(state, data).expect("Failed to reset efivars image");
+}
+
+/// Prints the QEMU command line `run` would use, without touching anything.
+pub fn show_cmdline(cfg: &Config, tmp: &Path, state: &Path, data: &Path, enable_gui: bool) {
+    let caps = match Capabilities::probe(cfg.machine.emulator()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    if let Err(e) = caps.check(cfg) {
+        warn!("{}", e);
+    }
+
+    let host = qemu::host_facts(cfg, &caps, efivars::path(state), data, &tmp.join("clientpipe.sock"),
+                                &tmp.join("monitor.sock"), enable_gui);
+    match cmdline::build(cfg, &host) {
+        Ok(cmdline) => println!("{}", cmdline.to_shell()),
+        Err(e) => error!("Invalid config: {}", e),
+    }
+}
+
+pub fn run(cfg: &Config, tmp: &Path, state: &Path, data: &Path, enable_gui: bool) {
+    let control_socket_file = tmp.join("control.sock");
+    // first check for running sessions
+    if is_running(&control_socket_file) {
+        error!("An instance of windows-gaming is already running in this runtime directory.");
+        error!("Either quit that or select a different runtime directory.");
+        return;
+    }
+
+    if let Err(e) = cmdline::validate(cfg) {
+        error!("Invalid config: {}", e);
+        return;
+    }
+    if let Err(e) = vfio::check_roms(&cfg.machine.pci_devices) {
+        error!("Invalid config: {}", e);
+        return;
+    }
+    if let Err(e) = vfio::verify(Path::new("/sys"), &cfg.machine.pci_devices) {
+        error!("{}", e);
+        return;
+    }
+    if cfg.machine.cpu_pinning.is_some() {
+        let host_cpus = pinning::host_cpus().expect("Failed to read online host CPUs");
+        if let Err(e) = pinning::validate(&cfg.machine, &host_cpus) {
+            error!("Invalid config: {}", e);
+            return;
+        }
+    }
+    if let Err(e) = hooks::validate(&cfg.hooks) {
+        error!("Invalid config: {}", e);
+        return;
+    }
+    if numa::uses_numa(&cfg.machine) {
+        let host = numa::HostTopology::read().expect("Failed to read host NUMA topology");
+        if let Err(e) = numa::check(&cfg.machine, &host) {
+            error!("Invalid config: {}", e);
             return;
         }
     }
 
+    // rather fail here than have QEMU choke on our command line
+    let caps = match Capabilities::probe(cfg.machine.emulator()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    if let Err(e) = caps.check(cfg) {
+        error!("{}", e);
+        return;
+    }
+
     let _ = fs::remove_dir_all(tmp); // may fail - we dont care
     fs::create_dir(tmp).expect("Failed to create TMP_FOLDER"); // may not fail - has to be new
     trace!("created tmp dir");
@@ -100,10 +194,37 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
         .expect("Failed to set permissions on control socket");
     debug!("Started Control socket");
 
+    // from here on, whatever completed pre_start hooks did is undone once `hooks` goes away, panics included
+    let mut hooks = hooks::Session::new(cfg, tmp, state);
+    if let Err(e) = hooks.pre_start() {
+        error!("{}", e);
+        return;
+    }
+
+    // fail with a proper message instead of QEMU dying on the preallocation
+    let hugepages = match hugepages::reserve_all(&cfg.machine) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    let qemu = qemu::run(cfg, tmp, data, &clientpipe_socket_file, &monitor_socket_file, &handle, enable_gui)
+    let sysbus = sleep_inhibitor::system_dbus();
+    let qemu = match qemu::run(cfg, &caps, state, data, &clientpipe_socket_file, &monitor_socket_file, &sysbus,
+                               &handle, enable_gui) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            teardown(cfg, data, &sysbus, hugepages, hooks);
+            return;
+        }
+    };
+    let qemu_pid = qemu.id();
+    let qemu = qemu
         .map(|code| {
             if !code.success() {
                 warn!("QEMU returned with an error code: {}", code);
@@ -113,14 +234,11 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
     let (monitor_stream, _) = monitor_socket.accept().expect("Failed to get monitor");
     drop(monitor_socket);
 
-    let (clientpipe_stream, _) = clientpipe_socket.accept().expect("Failed to get clientpipe");
-    drop(clientpipe_socket);
-
     sd_notify::notify_systemd(false, "Booting ...");
     debug!("Windows is starting");
 
     let mut monitor = Monitor::new(monitor_stream, &handle);
-    let mut clientpipe = Clientpipe::new(clientpipe_stream, &handle);
+    let mut clientpipe = Clientpipe::new(clientpipe_socket, &handle);
 
     let (mut input, input_events) = Input::new(&handle, cfg.machine.clone());
     input.suspend();
@@ -131,10 +249,8 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
     let (clipread_send, clipread_recv) = mpsc::unbounded();
     let (resp_send, resp_recv) = mpsc::unbounded();
 
-    let ctrl = Controller::new(cfg.machine.clone(), monitor_sender.clone(), clientpipe.take_send(), input.clone(),
-                               resp_send, clipgrab_send, clipread_send);
-
-    let controller = Rc::new(RefCell::new(ctrl));
+    let controller = Controller::new(cfg.machine.clone(), monitor.take_client(), clientpipe.take_send(),
+                                     input.clone(), resp_send, clipgrab_send, clipread_send, qemu_pid, &handle);
 
     let clipboard = X11Clipboard::open().expect("Failed to open X11 clipboard!");
     let clipboard_listener = clipboard.run(controller.clone(), resp_recv, &handle);
@@ -149,10 +265,14 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
         Ok(())
     }).then(|_| Ok(()));
 
-    let sysbus = sleep_inhibitor::system_dbus();
     let ctrl = controller.clone();
     let inhibitor = sleep_inhibitor::sleep_inhibitor(&sysbus, move || ctrl.borrow_mut().suspend(), &handle);
 
+    let sessionbus = dbus_service::session_dbus();
+    let dbus_service = dbus_service::create(sessionbus.as_ref(), controller.clone(), &handle);
+
+    let event_hooks = hooks::events(cfg, tmp, state, controller.clone(), &handle);
+
     let ref input_ref = *input;
     let input_listener = libinput::InputListener(input_ref);
     let hotkey_bindings: Vec<_> = cfg.machine.hotkeys.iter().map(|x| x.key.clone()).collect();
@@ -171,6 +291,8 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
 
     let joined = future::join_all(vec![
         inhibitor,
+        dbus_service,
+        event_hooks,
         clientpipe.take_handler(controller.clone(), &handle),
         clientpipe.take_sender(),
         control_handler,
@@ -184,7 +306,7 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
         Box::new(clipboard_reader),
     ]).map(|_| ());
 
-    core.run(qemu.select2(joined).then(|x| {
+    let res = core.run(qemu.select2(joined).then(|x| {
         match x {
             Ok(future::Either::A((_, _))) => info!("qemu down first, all ok"),
             Err(future::Either::A((e, _))) => return future::err(e).boxed(),
@@ -195,17 +317,26 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
             }
         }
         future::ok(()).boxed()
-    })).expect("Waiting for qemu errored");
+    }));
+    if let Err(e) = res {
+        error!("Waiting for QEMU failed: {}", e);
+    }
+
+    teardown(cfg, data, &sysbus, hugepages, hooks);
+    info!("windows-gaming-driver down.");
+}
+
+/// Gives the host back what we took for the VM, whether it ran or not
+fn teardown(cfg: &Config, data: &Path, sysbus: &libdbus::Connection, hugepages: Vec<hugepages::Reservation>,
+            mut hooks: hooks::Session) {
+    drop(hugepages);
 
     info!("unbinding resettable vfio-things");
     
-    for dev in cfg.machine.pci_devices.iter().filter(|x| x.resettable) {
-        let mut child = Command::new(data.join("vfio-ubind")).arg(&dev.slot).arg("-r").spawn().expect("failed to run vfio-ubind");
-        match child.wait() {
-            Ok(status) if status.success() => (), // all is well
-            Ok(status) => error!("vfio-ubind failed with {}! The device might still be bound to the vfio-driver!", status),
-            Err(err) => error!("failed to wait on child. Got: {}", err)
-        }
+    let resettable: Vec<_> = cfg.machine.pci_devices.iter().filter(|x| x.resettable).map(|x| x.slot.as_str()).collect();
+    if let Err(e) = vfio::ubind(sysbus, data, &resettable, true) {
+        error!("{}\nThe devices might still be bound to the vfio-driver!", e);
     }
-    info!("windows-gaming-driver down.");
+
+    hooks.post_stop();
 }
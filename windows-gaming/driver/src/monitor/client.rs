@@ -0,0 +1,121 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use futures::Future;
+use futures::future;
+use futures::unsync::oneshot;
+use futures::unsync::mpsc::UnboundedSender;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use super::codec::{QmpCommand, Request, ErrorDesc};
+
+/// Why a QMP command didn't produce a result
+#[derive(Debug)]
+pub enum QmpError {
+    /// QEMU rejected the command
+    Command(ErrorDesc),
+    /// QEMU replied with something we couldn't make sense of
+    InvalidReturn(serde_json::Error),
+    /// The monitor went away before QEMU replied
+    Disconnected,
+}
+
+impl Display for QmpError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            QmpError::Command(ref e) => write!(f, "{}: {}", e.class, e.desc),
+            QmpError::InvalidReturn(ref e) => write!(f, "invalid return value: {}", e),
+            QmpError::Disconnected => f.write_str("monitor disconnected"),
+        }
+    }
+}
+
+pub type QmpFuture<R> = Box<Future<Item=R, Error=QmpError>>;
+
+struct Pending {
+    command: &'static str,
+    // None if nobody is interested in the result
+    sender: Option<oneshot::Sender<Result<Value, QmpError>>>,
+}
+
+/// Commands we sent but didn't get a reply for yet
+#[derive(Default)]
+pub struct PendingCommands {
+    next_id: Cell<u64>,
+    commands: RefCell<HashMap<u64, Pending>>,
+}
+
+impl PendingCommands {
+    fn register(&self, command: &'static str,
+                sender: Option<oneshot::Sender<Result<Value, QmpError>>>) -> u64 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.commands.borrow_mut().insert(id, Pending { command, sender });
+        id
+    }
+
+    /// Hands a reply to whoever sent the command with the given id.
+    pub fn resolve(&self, id: u64, result: Result<Value, QmpError>) {
+        let pending = match self.commands.borrow_mut().remove(&id) {
+            Some(x) => x,
+            None => {
+                warn!("QEMU replied to unknown command id {}", id);
+                return;
+            }
+        };
+
+        let unhandled = match pending.sender {
+            Some(sender) => sender.send(result).err(),
+            None => Some(result),
+        };
+        // if nobody listens, at least make sure errors don't go unnoticed
+        if let Some(Err(e)) = unhandled {
+            warn!("QMP command {} failed: {}", pending.command, e);
+        }
+    }
+}
+
+/// Sends commands to QEMU and correlates the replies.
+#[derive(Clone)]
+pub struct QmpClient {
+    pending: Rc<PendingCommands>,
+    send: UnboundedSender<Request>,
+}
+
+impl QmpClient {
+    pub fn new(pending: Rc<PendingCommands>, send: UnboundedSender<Request>) -> QmpClient {
+        QmpClient { pending, send }
+    }
+
+    /// Executes a command, resolving to its return value once QEMU replied.
+    pub fn execute<R: DeserializeOwned + 'static>(&self, cmd: QmpCommand) -> QmpFuture<R> {
+        let (sender, receiver) = oneshot::channel();
+        if !self.submit(cmd, Some(sender)) {
+            return Box::new(future::err(QmpError::Disconnected));
+        }
+
+        Box::new(receiver.then(|res| match res {
+            Ok(Ok(ret)) => serde_json::from_value(ret).map_err(QmpError::InvalidReturn),
+            Ok(Err(e)) => Err(e),
+            Err(oneshot::Canceled) => Err(QmpError::Disconnected),
+        }))
+    }
+
+    /// Executes a command without waiting for the result. Errors are still logged.
+    pub fn send(&self, cmd: QmpCommand) {
+        self.submit(cmd, None);
+    }
+
+    fn submit(&self, cmd: QmpCommand, sender: Option<oneshot::Sender<Result<Value, QmpError>>>) -> bool {
+        let id = self.pending.register(cmd.name(), sender);
+        if (&self.send).send(Request { cmd, id: Some(id) }).is_err() {
+            self.pending.commands.borrow_mut().remove(&id);
+            warn!("Tried to send a QMP command but the monitor is gone");
+            return false;
+        }
+        true
+    }
+}
@@ -3,7 +3,7 @@ use std::str;
 use std::borrow::Cow;
 use bytes::BytesMut;
 use tokio_io::codec::{Encoder, Decoder};
-use serde_json;
+use serde_json::{self, Value};
 
 #[derive(Serialize)]
 #[serde(tag = "execute", content = "arguments", rename_all = "snake_case")]
@@ -20,12 +20,45 @@ pub enum QmpCommand {
     DeviceDel { id: String },
     SystemPowerdown,
     SystemWakeup,
+    Quit,
+    #[serde(rename = "query-commands")]
+    QueryCommands,
+    #[serde(rename = "query-cpus-fast")]
+    QueryCpusFast,
     #[serde(rename = "input-send-event")]
     InputSendEvent {
         events: Cow<'static, [InputEvent]>,
     },
 }
 
+impl QmpCommand {
+    /// The name QEMU knows this command by
+    pub fn name(&self) -> &'static str {
+        match *self {
+            QmpCommand::QmpCapabilities => "qmp_capabilities",
+            QmpCommand::DeviceAdd { .. } => "device_add",
+            QmpCommand::DeviceDel { .. } => "device_del",
+            QmpCommand::SystemPowerdown => "system_powerdown",
+            QmpCommand::SystemWakeup => "system_wakeup",
+            QmpCommand::Quit => "quit",
+            QmpCommand::QueryCommands => "query-commands",
+            QmpCommand::QueryCpusFast => "query-cpus-fast",
+            QmpCommand::InputSendEvent { .. } => "input-send-event",
+        }
+    }
+}
+
+/// A command as it goes over the wire.
+///
+/// QEMU echoes the `id` in its reply, which is how we find out what a reply belongs to.
+#[derive(Serialize)]
+pub struct Request {
+    #[serde(flatten)]
+    pub cmd: QmpCommand,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+}
+
 #[derive(Serialize, Clone)]
 #[serde(tag = "type", content = "data", rename_all = "snake_case")]
 pub enum InputEvent {
@@ -62,17 +95,40 @@ pub enum InputButton {
     Extra,
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum Message {
     Init { #[serde(rename = "QMP")] qmp: Qmp },
-    Return { #[serde(rename = "return")] ret: Ret },
+    Return { #[serde(rename = "return")] ret: Value, id: Option<u64> },
+    Error { error: ErrorDesc, id: Option<u64> },
     Event(Event),
 }
 
+/// The return value of commands that don't return anything
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 pub struct Ret {}
 
+/// An entry in the return value of `query-commands`
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub struct CommandInfo {
+    pub name: String,
+}
+
+/// An entry in the return value of `query-cpus-fast`
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CpuInfoFast {
+    pub cpu_index: usize,
+    /// host thread id of the vCPU
+    pub thread_id: i32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorDesc {
+    pub class: String,
+    pub desc: String,
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 #[serde(tag = "event")]
 pub enum Event {
@@ -100,8 +156,9 @@ pub enum Event {
 
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 pub struct DeviceDeleted {
-    device: String,
-    path: String,
+    /// Only devices that were given an id have one
+    pub device: Option<String>,
+    pub path: String,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq)]
@@ -169,11 +226,11 @@ impl Decoder for Codec {
 }
 
 impl Encoder for Codec {
-    type Item = QmpCommand;
+    type Item = Request;
     type Error = io::Error;
 
-    fn encode(&mut self, cmd: QmpCommand, buf: &mut BytesMut) -> io::Result<()> {
-        buf.extend(serde_json::to_string(&cmd).unwrap().bytes());
+    fn encode(&mut self, req: Request, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend(serde_json::to_string(&req).unwrap().bytes());
         Ok(())
     }
 }
@@ -203,10 +260,72 @@ mod test {
     fn ret() {
         let str = r#"{"return": {}}"#;
         let ser: Message = serde_json::from_str(str).unwrap();
-        let expected = Message::Return { ret: Ret {} };
+        let expected = Message::Return { ret: json!({}), id: None };
+        assert_eq!(ser, expected);
+    }
+
+    #[test]
+    fn ret_with_id() {
+        let str = r#"{"return": [{"cpu-index": 0}], "id": 3}"#;
+        let ser: Message = serde_json::from_str(str).unwrap();
+        let expected = Message::Return { ret: json!([{"cpu-index": 0}]), id: Some(3) };
+        assert_eq!(ser, expected);
+    }
+
+    #[test]
+    fn error() {
+        let str = r#"{"id": 7, "error": {"class": "GenericError", "desc": "Duplicate ID 'usb0' for device"}}"#;
+        let ser: Message = serde_json::from_str(str).unwrap();
+        let expected = Message::Error {
+            error: ErrorDesc {
+                class: "GenericError".to_string(),
+                desc: "Duplicate ID 'usb0' for device".to_string(),
+            },
+            id: Some(7),
+        };
         assert_eq!(ser, expected);
     }
 
+    #[test]
+    fn request() {
+        let req = Request { cmd: QmpCommand::DeviceDel { id: "usb0".to_string() }, id: Some(1) };
+        let ser = serde_json::to_value(&req).unwrap();
+        assert_eq!(ser, json!({"execute": "device_del", "arguments": {"id": "usb0"}, "id": 1}));
+    }
+
+    #[test]
+    fn request_untracked() {
+        let req = Request { cmd: QmpCommand::QmpCapabilities, id: None };
+        let ser = serde_json::to_value(&req).unwrap();
+        assert_eq!(ser, json!({"execute": "qmp_capabilities"}));
+    }
+
+    #[test]
+    fn query_commands() {
+        let req = Request { cmd: QmpCommand::QueryCommands, id: Some(2) };
+        let ser = serde_json::to_value(&req).unwrap();
+        assert_eq!(ser, json!({"execute": "query-commands", "id": 2}));
+
+        let ret: Vec<CommandInfo> = serde_json::from_str(r#"[{"name": "device_add"}, {"name": "quit"}]"#).unwrap();
+        assert_eq!(ret, vec![CommandInfo { name: "device_add".to_string() }, CommandInfo { name: "quit".to_string() }]);
+    }
+
+    #[test]
+    fn query_cpus_fast() {
+        let req = Request { cmd: QmpCommand::QueryCpusFast, id: Some(4) };
+        let ser = serde_json::to_value(&req).unwrap();
+        assert_eq!(ser, json!({"execute": "query-cpus-fast", "id": 4}));
+
+        let ret: Vec<CpuInfoFast> = serde_json::from_str(r#"[
+            {"arch": "x86", "thread-id": 25627, "props": {"core-id": 0, "thread-id": 0, "socket-id": 0},
+             "qom-path": "/machine/unattached/device[0]", "cpu-index": 0, "target": "x86_64"},
+            {"arch": "x86", "thread-id": 25628, "props": {"core-id": 0, "thread-id": 1, "socket-id": 0},
+             "qom-path": "/machine/unattached/device[2]", "cpu-index": 1, "target": "x86_64"}
+        ]"#).unwrap();
+        assert_eq!(ret, vec![CpuInfoFast { cpu_index: 0, thread_id: 25627 },
+                             CpuInfoFast { cpu_index: 1, thread_id: 25628 }]);
+    }
+
     #[test]
     fn powerdown() {
         let str = r#"{"timestamp": {"seconds": 1497035586, "microseconds": 395911}, "event": "POWERDOWN"}"#;
@@ -269,13 +388,30 @@ mod test {
                 microseconds: 508154,
             },
             data: DeviceDeleted {
-                device: "usb0".to_string(),
+                device: Some("usb0".to_string()),
                 path: "/machine/peripheral/usb0".to_string(),
             },
         });
         assert_eq!(ser, expected);
     }
 
+    #[test]
+    fn device_deleted_without_id() {
+        let str = r#"{"timestamp": {"seconds": 1497008409, "microseconds": 508154}, "event": "DEVICE_DELETED", "data": {"path": "/machine/peripheral-anon/device[0]/virtio-backend"}}"#;
+        let ser: Message = serde_json::from_str(str).unwrap();
+        let expected = Message::Event(Event::DeviceDeleted {
+            timestamp: Timestamp {
+                seconds: 1497008409,
+                microseconds: 508154,
+            },
+            data: DeviceDeleted {
+                device: None,
+                path: "/machine/peripheral-anon/device[0]/virtio-backend".to_string(),
+            },
+        });
+        assert_eq!(ser, expected);
+    }
+
     #[test]
     fn rtc_offset() {
         let str = r#"{"timestamp": {"seconds": 1497009700, "microseconds": 514}, "event": "RTC_CHANGE", "data": {"offset": -2}}"#;
@@ -1,4 +1,5 @@
 mod codec;
+mod client;
 
 pub use self::codec::{
     QmpCommand,
@@ -6,6 +7,9 @@ pub use self::codec::{
     Message,
     Event,
     Ret,
+    CommandInfo,
+    CpuInfoFast,
+    ErrorDesc,
     DeviceDeleted,
     RtcChange,
     Timestamp,
@@ -15,6 +19,7 @@ pub use self::codec::{
     KeyValue,
     InputButton,
 };
+pub use self::client::{QmpClient, QmpError, QmpFuture};
 
 use std::os::unix::net::{UnixStream as StdUnixStream};
 use std::io::{Error, ErrorKind};
@@ -28,7 +33,9 @@ use tokio_io::AsyncRead;
 use tokio_uds::UnixStream as TokioUnixStream;
 
 use controller::Controller;
-use self::codec::Codec;
+use control::ControlCmdOut;
+use self::codec::{Codec, Request};
+use self::client::PendingCommands;
 
 type Send = UnboundedSender<QmpCommand>;
 type Sender = Box<Future<Item=(), Error=Error>>;
@@ -37,22 +44,34 @@ type Handler = Box<Future<Item=(), Error=Error>>;
 
 pub struct Monitor {
     send: Option<Send>,
+    client: Option<QmpClient>,
     sender: Option<Sender>,
     read: Option<Read>,
+    pending: Rc<PendingCommands>,
 }
 
 impl Monitor {
     pub fn new(stream: StdUnixStream, handle: &Handle) -> Monitor {
         let stream = TokioUnixStream::from_stream(stream, handle).unwrap();
         let (write, read) = stream.framed(Codec).split();
+
+        // Fire-and-forget commands (like the flood of input events) don't get an id,
+        // everything going through the client does.
         let (send, recv) = mpsc::unbounded();
-        let recv = recv.map_err(|()| Error::new(ErrorKind::Other, "Failed to write to monitor"));
+        let recv = recv.map(|cmd| Request { cmd, id: None });
+        let (client_send, client_recv) = mpsc::unbounded();
+        let recv = recv.select(client_recv)
+            .map_err(|()| Error::new(ErrorKind::Other, "Failed to write to monitor"));
         let sender = write.send_all(recv).map(|_| ());
 
+        let pending = Rc::new(PendingCommands::default());
+
         Monitor {
             send: Some(send),
+            client: Some(QmpClient::new(pending.clone(), client_send)),
             sender: Some(Box::new(sender)),
             read: Some(Box::new(read)),
+            pending,
         }
     }
 
@@ -60,20 +79,45 @@ impl Monitor {
         self.send.take().unwrap()
     }
 
+    pub fn take_client(&mut self) -> QmpClient {
+        self.client.take().unwrap()
+    }
+
     pub fn take_sender(&mut self) -> Sender {
         self.sender.take().unwrap()
     }
 
     pub fn take_handler(&mut self, controller: Rc<RefCell<Controller>>) -> Handler {
+        let pending = self.pending.clone();
         let handler = self.read.take().unwrap().for_each(move |msg| {
-            if let Message::Return { .. } = msg {
-                // do not print these, they are useless and spammy
-            } else {
-                info!("{:?}", msg);
-            }
-
-            if let Message::Event(Event::Suspend { .. }) = msg {
-                controller.borrow_mut().qemu_suspended();
+            match msg {
+                Message::Return { id: Some(id), ret } => pending.resolve(id, Ok(ret)),
+                Message::Return { id: None, .. } => (), // do not print these, they are useless and spammy
+                Message::Error { id: Some(id), error } => pending.resolve(id, Err(QmpError::Command(error))),
+                Message::Error { id: None, error } => warn!("QMP command failed: {}: {}", error.class, error.desc),
+                Message::Event(Event::Suspend { .. }) => {
+                    info!("{:?}", msg);
+                    controller.borrow_mut().qemu_suspended();
+                }
+                Message::Event(Event::Wakeup { .. }) => {
+                    info!("{:?}", msg);
+                    controller.borrow_mut().emit(ControlCmdOut::QemuWakeup);
+                }
+                Message::Event(Event::Reset { .. }) => {
+                    info!("{:?}", msg);
+                    controller.borrow_mut().emit(ControlCmdOut::QemuReset);
+                }
+                Message::Event(Event::Powerdown { .. }) => {
+                    info!("{:?}", msg);
+                    controller.borrow_mut().emit(ControlCmdOut::QemuPowerdown);
+                }
+                Message::Event(Event::DeviceDeleted { ref data, .. }) => {
+                    debug!("{:?}", data);
+                    if let Some(ref device) = data.device {
+                        controller.borrow_mut().qemu_device_deleted(device);
+                    }
+                }
+                msg => info!("{:?}", msg),
             }
             Ok(())
         });
@@ -0,0 +1,136 @@
+//! Checks the guest NUMA layout against what the host actually has.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+
+use common::config::MachineConfig;
+use common::util;
+use pinning;
+
+/// NUMA nodes and SMT of the host, as far as we care
+#[derive(Debug)]
+pub struct HostTopology {
+    /// CPUs of every online node
+    nodes: BTreeMap<u32, Vec<usize>>,
+    threads_per_core: usize,
+}
+
+fn read_cpu_list(path: &Path) -> io::Result<Vec<usize>> {
+    let mut list = String::new();
+    File::open(path)?.read_to_string(&mut list)?;
+    util::parse_cpu_list(&list).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                                             format!("invalid list {:?} in {}", list, path.display())))
+}
+
+impl HostTopology {
+    pub fn read() -> io::Result<HostTopology> {
+        let root = Path::new("/sys/devices/system/node");
+        let mut nodes = BTreeMap::new();
+        if root.exists() {
+            for node in read_cpu_list(&root.join("online"))? {
+                nodes.insert(node as u32, read_cpu_list(&root.join(format!("node{}", node)).join("cpulist"))?);
+            }
+        } else {
+            // kernel without NUMA support, everything is on node 0
+            nodes.insert(0, pinning::host_cpus()?);
+        }
+
+        let siblings = Path::new("/sys/devices/system/cpu/cpu0/topology/thread_siblings_list");
+        let threads_per_core = read_cpu_list(siblings).map(|x| x.len()).unwrap_or(1);
+        Ok(HostTopology { nodes, threads_per_core })
+    }
+}
+
+/// Host nodes the config explicitly binds memory to
+fn host_nodes(machine: &MachineConfig) -> Vec<u32> {
+    let mut nodes: Vec<_> = machine.numa_nodes.iter().filter_map(|x| x.host_node)
+        .chain(machine.hugepages().and_then(|x| x.numa_node))
+        .collect();
+    nodes.sort();
+    nodes.dedup();
+    nodes
+}
+
+/// Whether there is anything NUMA related to check
+pub fn uses_numa(machine: &MachineConfig) -> bool {
+    !machine.numa_nodes.is_empty() || !host_nodes(machine).is_empty()
+}
+
+/// Errors out on nodes the host doesn't have and warns about topologies that will perform badly.
+pub fn check(machine: &MachineConfig, host: &HostTopology) -> Result<(), String> {
+    if let Some(node) = host_nodes(machine).into_iter().find(|x| !host.nodes.contains_key(x)) {
+        return Err(format!("host NUMA node {} doesn't exist or is offline, the host has nodes {}", node,
+                           host.nodes.keys().join(", ")));
+    }
+
+    let host_cpus: usize = host.nodes.values().map(|x| x.len()).sum();
+    if machine.vcpus() > host_cpus {
+        warn!("The guest has {} vCPUs but the host only {} CPUs", machine.vcpus(), host_cpus);
+    }
+    if machine.threads.unwrap_or(1) as usize > host.threads_per_core {
+        warn!("The guest has {} threads per core but the host only {}", machine.threads.unwrap_or(1),
+              host.threads_per_core);
+    }
+    if machine.numa_nodes.len() > host.nodes.len() {
+        warn!("The guest has {} NUMA nodes but the host only {}", machine.numa_nodes.len(), host.nodes.len());
+    }
+
+    if let Some(ref pinning) = machine.cpu_pinning {
+        for (i, node) in machine.numa_nodes.iter().enumerate() {
+            let host_node = match node.host_node {
+                Some(x) => x,
+                None => continue,
+            };
+            let cpus = &host.nodes[&host_node];
+            let remote: Vec<_> = node.vcpus.iter()
+                .filter(|&&vcpu| pinning.vcpus.get(vcpu).map_or(false, |cpu| !cpus.contains(cpu)))
+                .collect();
+            if !remote.is_empty() {
+                warn!("vCPUs {} of guest node {} are pinned to CPUs outside of host node {}, their memory will \
+                       be remote", remote.iter().join(", "), i, host_node);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::config::{NumaNode, CpuPinning};
+
+    fn host() -> HostTopology {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(0, vec![0, 1, 2, 3, 8, 9, 10, 11]);
+        nodes.insert(1, vec![4, 5, 6, 7, 12, 13, 14, 15]);
+        HostTopology { nodes, threads_per_core: 2 }
+    }
+
+    fn machine(host_nodes: &[u32]) -> MachineConfig {
+        MachineConfig {
+            memory: "8G".to_owned(),
+            cores: 4,
+            threads: Some(2),
+            numa_nodes: host_nodes.iter().enumerate().map(|(i, &node)| NumaNode {
+                vcpus: (i * 4..i * 4 + 4).collect(),
+                memory: "4G".to_owned(),
+                host_node: Some(node),
+            }).collect(),
+            cpu_pinning: Some(CpuPinning { vcpus: vec![0, 8, 1, 9, 4, 12, 5, 13], emulator: vec![], fifo_priority: None }),
+            ..MachineConfig::default()
+        }
+    }
+
+    #[test]
+    fn nodes() {
+        assert!(uses_numa(&machine(&[0, 1])));
+        assert!(!uses_numa(&MachineConfig::default()));
+        assert_eq!(host_nodes(&machine(&[1, 0, 1])), vec![0, 1]);
+        assert!(check(&machine(&[0, 1]), &host()).is_ok());
+        assert!(check(&machine(&[0, 2]), &host()).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Pins QEMU's vCPU and emulator threads to host CPUs.
+
+use std::fs;
+use std::io::{self, Read};
+use std::mem;
+use std::collections::HashSet;
+
+use libc;
+
+use common::config::{MachineConfig, CpuPinning};
+use common::util;
+use monitor::CpuInfoFast;
+
+/// Reads the host CPUs that are currently online.
+pub fn host_cpus() -> io::Result<Vec<usize>> {
+    let mut online = String::new();
+    fs::File::open("/sys/devices/system/cpu/online")?.read_to_string(&mut online)?;
+    util::parse_cpu_list(&online)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid cpu list {:?}", online)))
+}
+
+/// Checks the pinning against the `-smp` topology and the CPUs the host actually has.
+pub fn validate(machine: &MachineConfig, host_cpus: &[usize]) -> Result<(), String> {
+    let pinning = match machine.cpu_pinning {
+        Some(ref x) => x,
+        None => return Ok(()),
+    };
+
+    if pinning.vcpus.len() != machine.vcpus() {
+        return Err(format!("cpu_pinning lists {} vCPUs, but the -smp topology has {}",
+                           pinning.vcpus.len(), machine.vcpus()));
+    }
+
+    let mut used = HashSet::new();
+    for &cpu in &pinning.vcpus {
+        if !used.insert(cpu) {
+            return Err(format!("cpu_pinning puts more than one vCPU on host CPU {}", cpu));
+        }
+    }
+
+    if let Some(cpu) = pinning.vcpus.iter().chain(&pinning.emulator).find(|x| !host_cpus.contains(x)) {
+        return Err(format!("cpu_pinning uses host CPU {} which doesn't exist or is offline", cpu));
+    }
+
+    if let Some(prio) = pinning.fifo_priority {
+        if prio < 1 || prio > 99 {
+            return Err(format!("cpu_pinning: fifo_priority has to be between 1 and 99, not {}", prio));
+        }
+    }
+
+    if pinning.emulator.iter().any(|x| used.contains(x)) {
+        warn!("cpu_pinning: emulator threads share host CPUs with vCPUs, expect stutter");
+    }
+    Ok(())
+}
+
+fn set_affinity(tid: i32, cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(tid, mem::size_of::<libc::cpu_set_t>(), &set) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_fifo(tid: i32, priority: i32) -> io::Result<()> {
+    let param = libc::sched_param { sched_priority: priority };
+    if unsafe { libc::sched_setscheduler(tid, libc::SCHED_FIFO, &param) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// All thread ids of a process
+fn threads(pid: u32) -> io::Result<Vec<i32>> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|x| x.parse().ok()) {
+            tids.push(tid);
+        }
+    }
+    Ok(tids)
+}
+
+/// Applies the pinning to a running QEMU, given the vCPU threads it reported.
+///
+/// Threads QEMU creates later inherit the affinity of their creator, which is why it's
+/// enough to pin whatever exists right now.
+pub fn apply(pinning: &CpuPinning, qemu_pid: u32, vcpus: &[CpuInfoFast]) {
+    for vcpu in vcpus {
+        let cpu = match pinning.vcpus.get(vcpu.cpu_index) {
+            Some(&x) => x,
+            None => {
+                warn!("QEMU reported vCPU {} which isn't in cpu_pinning", vcpu.cpu_index);
+                continue;
+            }
+        };
+        match set_affinity(vcpu.thread_id, &[cpu]) {
+            Ok(()) => debug!("Pinned vCPU {} (thread {}) to CPU {}", vcpu.cpu_index, vcpu.thread_id, cpu),
+            Err(e) => warn!("Failed to pin vCPU {} to CPU {}: {}", vcpu.cpu_index, cpu, e),
+        }
+        if let Some(prio) = pinning.fifo_priority {
+            if let Err(e) = set_fifo(vcpu.thread_id, prio) {
+                warn!("Failed to make vCPU {} SCHED_FIFO: {}", vcpu.cpu_index, e);
+            }
+        }
+    }
+
+    if pinning.emulator.is_empty() {
+        return;
+    }
+    let others = match threads(qemu_pid) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to list QEMU threads, not pinning emulator threads: {}", e);
+            return;
+        }
+    };
+    for tid in others.into_iter().filter(|tid| !vcpus.iter().any(|x| x.thread_id == *tid)) {
+        if let Err(e) = set_affinity(tid, &pinning.emulator) {
+            warn!("Failed to pin emulator thread {}: {}", tid, e);
+        }
+    }
+    info!("Pinned {} vCPUs and emulator threads to {:?}", vcpus.len(), pinning.emulator);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn machine(vcpus: Vec<usize>, emulator: Vec<usize>, fifo_priority: Option<i32>) -> MachineConfig {
+        MachineConfig {
+            cores: 2,
+            threads: Some(2),
+            cpu_pinning: Some(CpuPinning { vcpus, emulator, fifo_priority }),
+            ..MachineConfig::default()
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let host: Vec<_> = (0..8).collect();
+        assert!(validate(&machine(vec![2, 6, 3, 7], vec![0, 4], Some(10)), &host).is_ok());
+        assert!(validate(&MachineConfig::default(), &host).is_ok());
+        // -smp has 4 vCPUs
+        assert!(validate(&machine(vec![2, 6, 3], vec![], None), &host).is_err());
+        assert!(validate(&machine(vec![2, 6, 3, 3], vec![], None), &host).is_err());
+        assert!(validate(&machine(vec![2, 6, 3, 8], vec![], None), &host).is_err());
+        assert!(validate(&machine(vec![2, 6, 3, 7], vec![9], None), &host).is_err());
+        assert!(validate(&machine(vec![2, 6, 3, 7], vec![], Some(100)), &host).is_err());
+    }
+}
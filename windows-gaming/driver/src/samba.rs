@@ -1,21 +1,10 @@
-use std::fs::{create_dir, File};
 use std::path::Path;
-use std::io::Write;
-use std::fmt::Write as FmtWrite;
-
-use users::get_user_by_name;
-use nix::unistd::chown;
-
-use common::config::SambaConfig;
 
 pub fn is_installed() -> bool {
     Path::new("/usr/sbin/smbd").is_file()
 }
 
-pub fn setup(tmp: &Path, samba: &SambaConfig, usernet: &mut String) {
-    //Samba still needs to be installed on the host OS
+pub fn setup() {
+    // QEMU's smb= option starts smbd on its own, it just needs to be installed on the host
     assert!(is_installed(), "Optional samba dependency not installed!");
-
-    write!(usernet, ",smb={}", samba.path).expect("Failed to append samba config to network adapter");
-
 }
@@ -31,3 +31,15 @@ pub fn notify_systemd(ready: bool, status: &'static str) {
         debug!("No libsystemd found.");
     }
 }
+
+/// Tells systemd we are on our way out and what we are currently waiting for.
+pub fn notify_stopping(status: &str) {
+    trace!("Notifying systemd (stopping status='{}')", status);
+    if let Some(sd_notify) = *SD_NOTIFY {
+        let state = CString::new(format!("STOPPING=1\nSTATUS={}", status)).unwrap();
+        let ret = sd_notify(0, state.as_ptr());
+        debug!("systemd returned {}", ret);
+    } else {
+        debug!("No libsystemd found.");
+    }
+}
@@ -0,0 +1,386 @@
+//! Sanity checks for the devices we pass through, and handing them over to vfio-pci.
+
+use std::fs;
+use std::io::Read;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use libudev::Context;
+use libdbus::{Connection, Message};
+
+use common::config::{PciId, VfioDevice};
+use common::pci_device::PciDevice;
+use common::util;
+use serde_json;
+
+/// The D-Bus helper that binds devices for us, as the setuid vfio-ubind is best avoided
+const HELPER_NAME: &str = "org.windowsgaming.Vfio1";
+const HELPER_PATH: &str = "/org/windowsgaming/Vfio1";
+const HELPER_INTERFACE: &str = "org.windowsgaming.Vfio1";
+
+/// Drivers the other devices in an IOMMU group may be bound to without getting in the way
+const GROUP_DRIVERS: &[&str] = &["vfio-pci", "pcieport"];
+
+/// Makes sure the configured ROM images can be read, QEMU only notices once the guest boots.
+pub fn check_roms(devices: &[VfioDevice]) -> Result<(), String> {
+    for device in devices {
+        if let Some(ref romfile) = device.options.romfile {
+            match fs::metadata(romfile) {
+                Ok(ref x) if x.is_file() => (),
+                Ok(_) => return Err(format!("{}: romfile {} is not a file", device.slot, romfile)),
+                Err(e) => return Err(format!("{}: can't access romfile {}: {}", device.slot, romfile, e)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A device sharing an IOMMU group with one we pass through
+struct GroupMember {
+    slot: String,
+    description: String,
+    driver: Option<String>,
+}
+
+fn driver(syspath: &Path) -> Option<String> {
+    fs::read_link(syspath.join("driver")).ok()
+        .and_then(|x| x.file_name().map(|x| x.to_string_lossy().into_owned()))
+}
+
+fn pci_id(syspath: &Path) -> Option<PciId> {
+    let read = |name: &str| {
+        let mut content = String::new();
+        fs::File::open(syspath.join(name)).and_then(|mut f| f.read_to_string(&mut content)).ok()?;
+        util::parse_hex(OsStr::new(content.trim()))
+    };
+    Some(PciId { vendor: read("vendor")?, device: read("device")? })
+}
+
+/// Vendor and model names if udev knows them, the ids are all we need after all
+fn describe(udev: &Context, syspath: &Path, id: &PciId) -> String {
+    udev.device_from_syspath(syspath).ok()
+        .map(|x| PciDevice::new(x).to_string())
+        .unwrap_or_else(|| format!("[{}]", id))
+}
+
+/// Group members that would keep VFIO from taking the group of `device`
+///
+/// vfio-ubind moves the whole group of a resettable device over to vfio-pci at startup,
+/// so whatever is bound there now doesn't matter.
+fn foreign_members<'a>(device: &VfioDevice, devices: &[VfioDevice], members: &'a [GroupMember])
+                       -> Vec<&'a GroupMember> {
+    if device.resettable {
+        return Vec::new();
+    }
+    members.iter()
+        .filter(|member| !devices.iter().any(|x| x.slot == member.slot))
+        .filter(|member| !member.driver.as_ref().map_or(false, |x| GROUP_DRIVERS.contains(&x.as_str())))
+        .collect()
+}
+
+/// Everything that's wrong with a configured device, nothing if it's good to go
+fn check_device(udev: &Context, sysfs: &Path, device: &VfioDevice, devices: &[VfioDevice]) -> Vec<String> {
+    let syspath = match fs::canonicalize(sysfs.join("bus/pci/devices").join(&device.slot)) {
+        Ok(x) => x,
+        Err(_) => return vec!["there is no device in this slot".to_owned()],
+    };
+    let id = match pci_id(&syspath) {
+        Some(x) => x,
+        None => return vec!["failed to read the ids of the device".to_owned()],
+    };
+
+    let mut problems = Vec::new();
+    if id != device.id {
+        problems.push(format!("found {} instead, did the card move to another slot?",
+                              describe(udev, &syspath, &id)));
+    }
+
+    let group = match fs::read_link(syspath.join("iommu_group")) {
+        Ok(x) => x.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default(),
+        Err(_) => {
+            problems.push("not in an IOMMU group, is the IOMMU enabled?".to_owned());
+            return problems;
+        }
+    };
+    let entries = match fs::read_dir(syspath.join("iommu_group").join("devices")) {
+        Ok(x) => x,
+        Err(e) => {
+            problems.push(format!("failed to list IOMMU group {}: {}", group, e));
+            return problems;
+        }
+    };
+    let mut members = Vec::new();
+    for path in entries.filter_map(Result::ok).filter_map(|x| fs::canonicalize(x.path()).ok()) {
+        match pci_id(&path) {
+            Some(id) => members.push(GroupMember {
+                slot: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                description: describe(udev, &path, &id),
+                driver: driver(&path),
+            }),
+            None => problems.push(format!("failed to read the ids of {}", path.display())),
+        }
+    }
+    for member in foreign_members(device, devices, &members) {
+        problems.push(format!("IOMMU group {} also contains {} {} ({}), which is neither passed through nor \
+                               bound to vfio-pci or pcieport", group, member.slot, member.description,
+                              member.driver.as_ref().map(|x| x.as_str()).unwrap_or("no driver")));
+    }
+    problems
+}
+
+/// Makes sure every configured slot holds the configured device and its IOMMU group can be
+/// handed to VFIO as a whole.
+///
+/// `sysfs` is where sysfs is mounted, `/sys` unless we're testing.
+pub fn verify(sysfs: &Path, devices: &[VfioDevice]) -> Result<(), String> {
+    if devices.is_empty() {
+        return Ok(());
+    }
+    let udev = Context::new().map_err(|e| format!("Failed to create udev context: {}", e))?;
+
+    let mut report = Vec::new();
+    let mut ok = true;
+    for device in devices {
+        let problems = check_device(&udev, sysfs, device, devices);
+        if problems.is_empty() {
+            report.push(format!("{} [{}]: ok", device.slot, device.id));
+        }
+        for problem in problems {
+            ok = false;
+            report.push(format!("{} [{}]: {}", device.slot, device.id, problem));
+        }
+    }
+
+    if ok {
+        debug!("PCI devices:\n\t{}", report.join("\n\t"));
+        Ok(())
+    } else {
+        Err(format!("Refusing to pass through PCI devices:\n\t{}", report.join("\n\t")))
+    }
+}
+
+/// What vfio-ubind did to one device of a group
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct UbindMember {
+    pub slot: String,
+    pub driver_before: Option<String>,
+    pub driver_after: Option<String>,
+}
+
+/// What vfio-ubind did for one of the devices we asked for
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct UbindDevice {
+    pub device: String,
+    pub members: Vec<UbindMember>,
+    pub error: Option<String>,
+}
+
+/// The `--json` report of vfio-ubind
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct UbindReport {
+    pub remove: bool,
+    pub dry_run: bool,
+    pub devices: Vec<UbindDevice>,
+}
+
+impl UbindReport {
+    fn errors(&self) -> Vec<String> {
+        self.devices.iter()
+            .filter_map(|x| x.error.as_ref().map(|e| format!("{}: {}", x.device, e)))
+            .collect()
+    }
+}
+
+/// Asks the vfio D-Bus helper to do it, `Ok(None)` if it isn't installed or polkit won't let us.
+fn ubind_dbus(bus: &Connection, slots: &[&str], remove: bool) -> Result<Option<UbindReport>, String> {
+    let method = if remove { "UnbindVfio" } else { "BindVfio" };
+    let call = Message::new_method_call(HELPER_NAME, HELPER_PATH, HELPER_INTERFACE, method).unwrap()
+        .append1(slots.to_vec());
+    // polkit may ask for a password first
+    match bus.send_with_reply_and_block(call, 120_000) {
+        Ok(reply) => {
+            let report = reply.get1::<&str>().ok_or("vfio helper sent no report")?;
+            serde_json::from_str(report).map(Some).map_err(|e| format!("vfio helper sent an invalid report: {}", e))
+        }
+        Err(ref e) if e.name() == Some("org.freedesktop.DBus.Error.ServiceUnknown") => Ok(None),
+        // e.g. no polkit agent to ask for a password when running as a service, the vfio group may still do it
+        Err(ref e) if e.name() == Some("org.freedesktop.DBus.Error.AccessDenied") => {
+            warn!("vfio helper refused: {}", e.message().unwrap_or("access denied"));
+            Ok(None)
+        }
+        Err(e) => Err(format!("vfio helper failed: {}", e.message().unwrap_or("no reply"))),
+    }
+}
+
+/// Runs the setuid vfio-ubind, for systems without the D-Bus helper.
+fn ubind_setuid(data: &Path, slots: &[&str], remove: bool) -> Result<UbindReport, String> {
+    let mut cmd = Command::new(data.join("vfio-ubind"));
+    cmd.arg("--json").args(slots).stdin(Stdio::null()).stdout(Stdio::piped());
+    if remove {
+        cmd.arg("-r");
+    }
+    let output = cmd.output().map_err(|e| format!("failed to run vfio-ubind: {}", e))?;
+
+    // the report is there even if some devices failed, it's only missing if vfio-ubind refused to start
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("vfio-ubind failed with {} without a report ({})", output.status, e))
+}
+
+/// Binds the devices (and their IOMMU groups) to vfio-pci, or gives them back to their
+/// previous drivers if `remove` is set, all in one go.
+///
+/// Goes through the D-Bus helper if it's installed and falls back to the setuid vfio-ubind.
+pub fn ubind(bus: &Connection, data: &Path, slots: &[&str], remove: bool) -> Result<UbindReport, String> {
+    if slots.is_empty() {
+        return Ok(UbindReport { remove, dry_run: false, devices: Vec::new() });
+    }
+
+    let report = match ubind_dbus(bus, slots, remove)? {
+        Some(x) => x,
+        None => {
+            debug!("{} isn't available, running the setuid vfio-ubind", HELPER_NAME);
+            ubind_setuid(data, slots, remove)?
+        }
+    };
+    for device in &report.devices {
+        for member in &device.members {
+            if member.driver_before != member.driver_after {
+                info!("{}: {} -> {}", member.slot, member.driver_before.as_ref().map_or("none", |x| x.as_str()),
+                      member.driver_after.as_ref().map_or("none", |x| x.as_str()));
+            }
+        }
+    }
+
+    let errors = report.errors();
+    if !errors.is_empty() {
+        return Err(format!("Failed to rebind PCI devices:\n\t{}", errors.join("\n\t")));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::os::unix::fs::symlink;
+    use common::config::VfioOptions;
+
+    /// A sysfs with a GPU, its audio function and the root port above them, all in one IOMMU group
+    fn fake_sysfs(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("windows-gaming-vfio-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let group = root.join("kernel/iommu_groups/1");
+        fs::create_dir_all(group.join("devices")).unwrap();
+        for &(slot, id, driver) in &[("0000:00:01.0", "0x1901", "pcieport"),
+                                     ("0000:01:00.0", "0x1b80", "nouveau"),
+                                     ("0000:01:00.1", "0x10f0", "snd_hda_intel")] {
+            let dev = root.join("bus/pci/devices").join(slot);
+            let driver = root.join("bus/pci/drivers").join(driver);
+            fs::create_dir_all(&dev).unwrap();
+            fs::create_dir_all(&driver).unwrap();
+            fs::File::create(dev.join("vendor")).unwrap().write_all(b"0x10de\n").unwrap();
+            fs::File::create(dev.join("device")).unwrap().write_all(format!("{}\n", id).as_bytes()).unwrap();
+            symlink(driver, dev.join("driver")).unwrap();
+            symlink(&group, dev.join("iommu_group")).unwrap();
+            symlink(&dev, group.join("devices").join(slot)).unwrap();
+        }
+        root
+    }
+
+    fn device(slot: &str, device: u16) -> VfioDevice {
+        VfioDevice {
+            resettable: false,
+            slot: slot.to_owned(),
+            id: PciId { vendor: 0x10de, device },
+            options: VfioOptions::default(),
+        }
+    }
+
+    #[test]
+    fn verify_devices() {
+        let sysfs = fake_sysfs("verify");
+        let gpu = device("0000:01:00.0", 0x1b80);
+        let audio = device("0000:01:00.1", 0x10f0);
+        assert_eq!(verify(&sysfs, &[gpu.clone(), audio.clone()]), Ok(()));
+
+        let err = verify(&sysfs, &[gpu.clone(), audio.clone(), device("0000:02:00.0", 0x1b80)]).unwrap_err();
+        assert!(err.contains("0000:02:00.0 [10de:1b80]: there is no device in this slot"), "{}", err);
+
+        let err = verify(&sysfs, &[device("0000:01:00.0", 0x1b81), audio.clone()]).unwrap_err();
+        assert!(err.contains("did the card move to another slot?"), "{}", err);
+
+        // the audio function is still bound to the host
+        let err = verify(&sysfs, &[gpu.clone()]).unwrap_err();
+        assert!(err.contains("also contains 0000:01:00.1"), "{}", err);
+        assert!(err.contains("(snd_hda_intel)"), "{}", err);
+
+        let resettable = VfioDevice { resettable: true, ..gpu };
+        assert_eq!(verify(&sysfs, &[resettable]), Ok(()));
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+
+    fn member(slot: &str, driver: Option<&str>) -> GroupMember {
+        GroupMember {
+            slot: slot.to_owned(),
+            description: "Some device".to_owned(),
+            driver: driver.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn group() {
+        let mut devices = vec![VfioDevice {
+            resettable: false,
+            slot: "0000:01:00.0".to_owned(),
+            id: PciId { vendor: 0x10de, device: 0x1b80 },
+            options: VfioOptions::default(),
+        }];
+        let members = vec![
+            member("0000:00:01.0", Some("pcieport")),
+            member("0000:01:00.0", Some("nouveau")),
+            member("0000:01:00.1", Some("snd_hda_intel")),
+            member("0000:01:00.2", Some("vfio-pci")),
+            member("0000:01:00.3", None),
+        ];
+        let foreign: Vec<_> = foreign_members(&devices[0], &devices, &members).into_iter()
+            .map(|x| x.slot.as_str()).collect();
+        assert_eq!(foreign, vec!["0000:01:00.1", "0000:01:00.3"]);
+
+        // vfio-ubind takes care of the rest of the group
+        devices[0].resettable = true;
+        assert!(foreign_members(&devices[0], &devices, &members).is_empty());
+    }
+
+    #[test]
+    fn ubind_report() {
+        let report: UbindReport = serde_json::from_str(r#"{
+            "remove": false,
+            "dry_run": false,
+            "devices": [
+                {
+                    "device": "0000:01:00.0",
+                    "members": [
+                        {"slot": "0000:01:00.0", "driver_before": "nouveau", "driver_after": "vfio-pci"},
+                        {"slot": "0000:01:00.1", "driver_before": null, "driver_after": "vfio-pci"}
+                    ],
+                    "error": null
+                },
+                {
+                    "device": "0000:02:00.0",
+                    "members": [],
+                    "error": "The device does not support resetting!"
+                }
+            ]
+        }"#).unwrap();
+        assert_eq!(report.devices[0].members[1], UbindMember {
+            slot: "0000:01:00.1".to_owned(),
+            driver_before: None,
+            driver_after: Some("vfio-pci".to_owned()),
+        });
+        assert_eq!(report.errors(), vec!["0000:02:00.0: The device does not support resetting!"]);
+    }
+}
@@ -11,8 +11,8 @@ extern crate wizard;
 mod logger;
 
 use std::path::Path;
-use std::os::unix::net::UnixStream;
-use std::io::{self, Write};
+use std::process;
+use std::io;
 
 use clap::{Arg, App, SubCommand, AppSettings, ArgGroup, Shell};
 use nix::unistd;
@@ -55,6 +55,14 @@ fn main() {
                 .takes_value(false))
         ).subcommand(SubCommand::with_name("wizard")
             .about("Runs the wizard")
+        ).subcommand(SubCommand::with_name("show-cmdline")
+            .about("Prints the QEMU command line for the current config without running anything")
+            .arg(Arg::with_name("virtual-gpu")
+                .long("virtual-gpu")
+                .help("Show the command line with a virtual GPU")
+                .takes_value(false))
+        ).subcommand(SubCommand::with_name("reset-nvram")
+            .about("Restores the VM's EFI variables (boot order, Secure Boot keys, ...) to the pristine template")
         ).subcommand(SubCommand::with_name("control")
             .about("Commands to interact with the driver")
             .subcommand(SubCommand::with_name("attach")
@@ -83,6 +91,15 @@ fn main() {
                 .about("Shuts down Windows, gracefully stopping execution of the driver")
             ).subcommand(SubCommand::with_name("suspend")
                 .about("Suspends Windows")
+            ).subcommand(SubCommand::with_name("status")
+                .about("Shows the state of the running driver")
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .help("Prints the status as JSON")
+                    .takes_value(false)
+                )
+            ).subcommand(SubCommand::with_name("events")
+                .about("Prints driver events as they happen, one JSON object per line")
             )
         );
     let matches = cli.clone().get_matches();
@@ -119,6 +136,11 @@ fn main() {
     };
     debug!("Working directory is {:?}", workdir_path);
 
+    let state_path = match mode {
+        RunMode::System => Path::new("/var/lib/windows-gaming-driver").to_path_buf(),
+        RunMode::User => xdg_dirs.create_data_directory("").expect("Failed to create state directory."),
+    };
+
     let cfg = Config::load(&config_path);
     trace!("Successfully loaded configuration file.");
 
@@ -134,9 +156,20 @@ fn main() {
         _ => workdir_path,
     };
 
+    let state_path = match cfg {
+        Some(Config { state_directory_override: Some(ref x), .. }) => Path::new(x).to_path_buf(),
+        _ => state_path,
+    };
+    debug!("State directory is {:?}", state_path);
+
     match matches.subcommand() {
-        ("run", cmd) => driver::run(cfg.as_ref().unwrap(), &workdir_path, &data_folder, cmd.unwrap().is_present("virtual-gpu")),
-        ("wizard", _) => wizard::run(cfg, &config_path, &workdir_path, &data_folder),
+        ("run", cmd) => driver::run(cfg.as_ref().unwrap(), &workdir_path, &state_path, &data_folder,
+                                    cmd.unwrap().is_present("virtual-gpu")),
+        ("wizard", _) => wizard::run(cfg, &config_path, &workdir_path, &state_path, &data_folder),
+        ("show-cmdline", cmd) => driver::show_cmdline(cfg.as_ref().expect("No config found"), &workdir_path,
+                                                      &state_path, &data_folder,
+                                                      cmd.unwrap().is_present("virtual-gpu")),
+        ("reset-nvram", _) => driver::reset_nvram(&workdir_path, &state_path, &data_folder),
         ("control", cmd) => {
             match cmd.unwrap().subcommand() {
                 ("attach", cmd) => {
@@ -154,27 +187,51 @@ fn main() {
                 ("detach", _) => control_send(ControlCmdIn::IoExit, &control_socket),
                 ("shutdown", _) => control_send(ControlCmdIn::Shutdown, &control_socket),
                 ("suspend", _) => control_send(ControlCmdIn::Suspend, &control_socket),
+                ("status", cmd) => {
+                    let status = control_send(ControlCmdIn::Status, &control_socket)
+                        .expect("Driver did not include a status in its reply");
+                    if cmd.unwrap().is_present("json") {
+                        println!("{}", status.to_json());
+                    } else {
+                        println!("{}", status);
+                    }
+                }
+                ("events", _) => control_subscribe(&control_socket),
                 _ => unreachable!()
             }
         }
         _ => match cfg {
-            Some(ref cfg) if cfg.setup.is_none() => driver::run(cfg, &workdir_path, &data_folder, false),
-            cfg => wizard::run(cfg, &config_path, &workdir_path, &data_folder),
+            Some(ref cfg) if cfg.setup.is_none() => driver::run(cfg, &workdir_path, &state_path, &data_folder, false),
+            cfg => wizard::run(cfg, &config_path, &workdir_path, &state_path, &data_folder),
+        }
+    }
+}
+
+fn control_send<P: AsRef<Path>>(cmd: ControlCmdIn, socket_path: P) -> Option<driver::Status> {
+    match driver::send_command(socket_path, cmd) {
+        Ok(Ok(status)) => status,
+        Ok(Err(e)) => {
+            eprintln!("The driver refused: {}", e.message);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to talk to the driver: {}", e);
+            process::exit(1);
         }
     }
 }
 
-fn control_send<P: AsRef<Path>>(cmd: ControlCmdIn, socket_path: P) {
-    let mut writer = UnixStream::connect(socket_path).unwrap();
-    writer.write(&[match cmd {
-        ControlCmdIn::IoEntry => 1,
-        ControlCmdIn::TryIoEntry => 6,
-        ControlCmdIn::LightEntry => 7,
-        ControlCmdIn::Shutdown => 2,
-        ControlCmdIn::ForceIoEntry => 3,
-        ControlCmdIn::IoExit => 4,
-        ControlCmdIn::Suspend => 5,
-        ControlCmdIn::TemporaryLightEntry { .. } => unimplemented!()
-    }]).unwrap();
-    writer.flush().unwrap();
+fn control_subscribe<P: AsRef<Path>>(socket_path: P) {
+    let res = driver::subscribe(socket_path, |event| println!("{}", event.to_json()));
+    match res {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => {
+            eprintln!("The driver refused: {}", e.message);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to talk to the driver: {}", e);
+            process::exit(1);
+        }
+    }
 }
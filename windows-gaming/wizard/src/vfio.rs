@@ -4,7 +4,7 @@ use std::fs::File;
 use std::io::{BufReader, BufRead, Write, Result};
 use libudev::{Context, Enumerator};
 
-use common::config::{MachineConfig, VfioDevice};
+use common::config::{MachineConfig, VfioDevice, VfioOptions};
 use common::pci_device::PciDevice;
 use ask;
 use wizard;
@@ -134,6 +134,7 @@ fn select_device<P>(machine: &mut MachineConfig, question: &str, devices: &[PciD
                 resettable: device.resettable,
                 slot: device.pci_slot.clone(),
                 id: device.id,
+                options: VfioOptions::default(),
             };
 
             machine.pci_devices.push(vfio_device);
@@ -28,7 +28,7 @@ pub fn setup(setup: &mut SetupConfig, machine: &mut MachineConfig, datadir: &Pat
         return false;
     }
 
-    if qemu::has_gtk_support() && env::var("DISPLAY").is_ok() {
+    if qemu::has_gtk_support(machine) && env::var("DISPLAY").is_ok() {
         println!("It seems you're running this setup in a graphical environment. This can make things a lot easier!");
         println!("While our objective is of course VGA passthrough, running a virtual display during setup is very convenient for many reasons. We strongly recommend using this.");
         if ask::yesno("Would you like to enable virtual graphics (only during setup)?") {
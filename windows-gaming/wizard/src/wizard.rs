@@ -19,7 +19,7 @@ use vm;
 struct Wizard;
 
 impl Wizard {
-    fn run(&mut self, cfg: Option<Config>, cfg_path: &Path, workdir: &Path, datadir: &Path) {
+    fn run(&mut self, cfg: Option<Config>, cfg_path: &Path, workdir: &Path, statedir: &Path, datadir: &Path) {
         let mut config = cfg.unwrap_or_default();
         if config.setup.is_none() {
             config.setup = Some(SetupConfig::default());
@@ -107,7 +107,7 @@ impl Wizard {
                 return;
             }
 
-            driver::run(&config, workdir, datadir, config.setup.as_ref().unwrap().gui);
+            driver::run(&config, workdir, statedir, datadir, config.setup.as_ref().unwrap().gui);
 
             // TODO:
             // * ask if it worked, offer to retry or abort
@@ -143,6 +143,6 @@ pub fn sudo_write_file<P: AsRef<Path>, F: FnOnce(&mut ChildStdin) -> IoResult<()
     Ok(writer_child.wait()?.success())
 }
 
-pub fn run(cfg: Option<Config>, target: &Path, workdir: &Path, datadir: &Path) {
-    Wizard.run(cfg, target, workdir, datadir);
+pub fn run(cfg: Option<Config>, target: &Path, workdir: &Path, statedir: &Path, datadir: &Path) {
+    Wizard.run(cfg, target, workdir, statedir, datadir);
 }